@@ -1,110 +1,1502 @@
-use crate::parser::{Program, Function, Statement, Expression, Op};
+// 16-bit real-mode binary backend
+//
+// Calling convention (real mode, cdecl):
+//   * Arguments are pushed right to left, so the first argument ends up at the
+//     lowest address. Every argument takes a whole number of 16-bit stack words:
+//     u8, u16 and near pointers take one word, far pointers two (offset first).
+//   * `call` pushes the return address, the callee pushes BP, so inside the
+//     callee the first argument lives at [bp+4], the next one after it.
+//   * The caller pops its own arguments after the call returns.
+//   * Results come back in AX, far pointers in DX:AX. AX, BX, CX, DX and ES are
+//     caller-saved; BP, SP, SI, DI, DS and SS are preserved by the callee.
+//
+// Calling convention (64-bit, asm backend, see codegen_asm.rs):
+//   * The first six arguments go in RDI, RSI, RDX, RCX, R8 and R9, the rest are
+//     pushed right to left. The result comes back in RAX.
+//   * RBP and R12-R15 are callee-saved, everything else is caller-saved. RBX
+//     is scratch too: every expression uses it for its right-hand operand.
+//   * The callee spills its register arguments into its own frame on entry, so
+//     the body addresses every parameter like a local, relative to RBP.
+//
+// Frame layout (real mode):
+//
+//   [bp+4 ...]  arguments, first one lowest
+//   [bp+2]      return address
+//   [bp+0]      caller's BP
+//   [bp-1 ...]  locals, allocated downwards in declaration order
+//
+// Each `let` gets its own slot sized by its type (u8 one byte, u16 and near
+// pointers a word, far pointers two, word aligned). A nested block's locals sit
+// below the enclosing block's and their slots are handed out again once the
+// block closes, so the frame only needs room for the deepest nesting.
+// Expressions are evaluated in AX, so sema rejects 32 and 64-bit integers on
+// this target rather than have them silently lose their high words.
+//
+// Loads and stores through pointers and globals use the width of the type in
+// memory: a `*u8` store writes one byte and leaves its neighbour alone, and a
+// byte load is zero- or sign-extended into AX.
+//
+// The input is the typed tree from sema.rs, so both operands of a binary
+// operator already have the same type. Comparisons, `* / %` and `>>` pick
+// signed or unsigned instructions from it. AX always holds a value extended
+// from its type's width: 8-bit results are re-extended after arithmetic and
+// casts. `&&` and `||` short-circuit. Conditional jumps are emitted as a short
+// `jcc` over a near `jmp`, which reaches the whole segment and runs on an 8086.
+//
+// Near pointers are DS-relative offsets, and DS is 0 (see examples/boot.asm),
+// so they reach the first 64 KiB. A `far *T` is a segment:offset pair: offset
+// in the low word, segment in the high word, the layout `les` loads. It is
+// evaluated into DX:AX and dereferenced through ES with a segment override.
+// Far literals are split as segment = address >> 4, offset = address & 0xF, and
+// `ptr + n` only moves the offset. `#[address(N)]` globals above 0xFFFF are
+// reached the same way.
+//
+// Port I/O builtins use `in`/`out` with an immediate port when it is a literal
+// below 256 and DX otherwise. `inl`, `outl` and u32 port globals need u32
+// values, so sema rejects them on this target.
+//
+// `#[interrupt]` handlers save every register they may clobber, load DS with 0
+// (the interrupted code may have had another data segment), and return with
+// `iret`. Handlers with a vector are installed by a startup stub that writes
+// their 0000:offset into the IVT before jumping to kernel_main.
+//
+// The image is laid out as: the startup stub ending in a `jmp` to kernel_main,
+// the functions, the runtime routines they use (runtime.rs), read-only data
+// (`const` globals and strings), then the initialised globals. Globals without an initialiser (and without
+// `#[address]` or `#[port]`) live in a bss area right after the image, which
+// the startup stub zeroes; the image and both areas must end at IMAGE_LIMIT,
+// clear of the boot stack.
+// String literals live in the read-only data, NUL-terminated and stored once
+// however often they appear; byte strings likewise, without the NUL. Code
+// refers to data by absolute address from ORIGIN, patched at the end, as are
+// string addresses in global initializers.
+//
+// Loops keep a context on `loops` while their body is generated; `break` and
+// `continue` emit placeholder jumps into the innermost (or labelled) context,
+// which are patched once the loop's exit and continue points are known.
+
+use crate::diagnostics::Diagnostic;
+use crate::parser::{Program, Function, Statement, Format, Op, UnaryOp, Type, Attribute};
+use crate::sema::{Expr, ExprKind, Builtin};
 use std::collections::HashMap;
 
+mod runtime;
+
+use runtime::Routine;
+
+/// Address the kernel is loaded at (see examples/boot.asm), with CS = DS = 0.
+const ORIGIN: u16 = 0x1000;
+
+/// Where the image and its bss must end. boot.asm leaves SS:SP at 0000:7C00,
+/// just below the boot sector, and the stack grows down from there; the 4 KiB
+/// in between are kept for it.
+const IMAGE_LIMIT: usize = 0x7C00 - 0x1000;
+
+/// A memory operand: `[bp+disp]`, `[bx+disp]` or an absolute `[addr]`, the
+/// last two either in DS or, for far accesses, in ES. `Data` and `Rodata` are
+/// absolute operands given as an offset into the data area or the read-only
+/// data, patched once those are placed.
+#[derive(Clone, Copy)]
+enum Mem { Bp(i16), Bx(i16), Abs(u16), EsBx(i16), EsAbs(u16), Data(u16), Rodata(u16) }
+
+impl Mem {
+    fn offset(self, by: i16) -> Mem {
+        match self {
+            Mem::Bp(disp) => Mem::Bp(disp.wrapping_add(by)),
+            Mem::Bx(disp) => Mem::Bx(disp.wrapping_add(by)),
+            Mem::Abs(addr) => Mem::Abs(addr.wrapping_add(by as u16)),
+            Mem::EsBx(disp) => Mem::EsBx(disp.wrapping_add(by)),
+            Mem::EsAbs(addr) => Mem::EsAbs(addr.wrapping_add(by as u16)),
+            Mem::Data(offset) => Mem::Data(offset.wrapping_add(by as u16)),
+            Mem::Rodata(offset) => Mem::Rodata(offset.wrapping_add(by as u16)),
+        }
+    }
+}
+
+/// An enclosing loop that `break` and `continue` can jump out of.
+struct LoopContext { label: Option<String>, breaks: Vec<u16>, continues: Vec<u16> }
+
 pub struct Codegen {
     code: Vec<u8>,
     functions: HashMap<String, u16>,
     current_offset: u16,
+    /// Block scopes of the current function, innermost last: name -> BP-relative slot.
+    scopes: Vec<HashMap<String, (i16, Type)>>,
+    /// Lowest BP offset handed out to a local in the currently open blocks.
+    frame_top: i32,
+    /// Memory-mapped `#[address(N)]` globals, by physical address.
+    globals: HashMap<String, u64>,
+    /// Size of every struct, as laid out by sema.
+    structs: HashMap<String, usize>,
+    /// `const` globals, by offset into `rodata`.
+    constants: HashMap<String, u16>,
+    /// Other globals, by offset into the data area (initialised ones first, then the bss).
+    variables: HashMap<String, u16>,
+    /// Absolute `imm16` operands that hold an offset into the data area.
+    data_fixups: Vec<(u16, u16)>,
+    /// `call rel16` operands to patch once every function has an offset.
+    call_fixups: Vec<(u16, String)>,
+    /// Absolute `imm16` operands that hold the address of a function.
+    address_fixups: Vec<(u16, String)>,
+    /// The function being generated is an `#[interrupt]` handler.
+    interrupt: bool,
+    /// Runtime routines called so far, in order of first use.
+    routines: Vec<Routine>,
+    routine_fixups: Vec<(u16, Routine)>,
+    /// Read-only data placed after the code, and the offset of each string and
+    /// byte string in it (by contents, a string with its NUL).
+    rodata: Vec<u8>,
+    strings: HashMap<Vec<u8>, u16>,
+    /// Absolute `imm16` operands that hold an offset into `rodata`.
+    rodata_fixups: Vec<(u16, u16)>,
+    loops: Vec<LoopContext>,
+    errors: Vec<Diagnostic>,
 }
 
 impl Codegen {
     pub fn new() -> Self {
-        Codegen { code: Vec::new(), functions: HashMap::new(), current_offset: 0 }
+        Codegen {
+            code: Vec::new(), functions: HashMap::new(), current_offset: 0,
+            scopes: Vec::new(), frame_top: 0, globals: HashMap::new(), structs: HashMap::new(), constants: HashMap::new(), variables: HashMap::new(), data_fixups: Vec::new(),
+            call_fixups: Vec::new(), address_fixups: Vec::new(), interrupt: false, routines: Vec::new(), routine_fixups: Vec::new(),
+            rodata: Vec::new(), strings: HashMap::new(), rodata_fixups: Vec::new(),
+            loops: Vec::new(), errors: Vec::new(),
+        }
+    }
+
+    /// Bytes a value of type `ty` occupies on the stack.
+    fn slot_size(ty: &Type) -> i16 {
+        match ty { Type::U32 | Type::I32 | Type::FarPointer(_) => 4, Type::U64 | Type::I64 => 8, _ => 2 }
+    }
+
+    /// Bytes a value of type `ty` occupies in memory. Sema keeps types below
+    /// 64 KiB, but that is still more than an `i16` holds.
+    fn size_of(&self, ty: &Type) -> usize {
+        match ty {
+            Type::U8 | Type::I8 | Type::Bool => 1,
+            Type::Struct(name) => self.structs[name],
+            Type::Array(element, len) => self.size_of(element) * *len as usize,
+            _ => Self::slot_size(ty) as usize,
+        }
     }
 
-    pub fn compile(&mut self, program: &Program) -> Vec<u8> {
+    /// Allocates a local of type `ty` below `top`, returning its BP offset.
+    /// The offset may be out of `[bp+disp16]` range; `generate_function`
+    /// rejects such frames before any slot is used.
+    fn alloc_slot(&self, top: i32, ty: &Type) -> i32 {
+        let size = self.size_of(ty) as i32;
+        if size > 1 { (top - size) & !1 } else { top - size }
+    }
+
+    /// Deepest BP offset the locals of `stmts` reach when allocated from `top`.
+    fn frame_extent(&self, stmts: &[Statement<Expr>], mut top: i32) -> i32 {
+        let mut deepest = top;
+        for stmt in stmts {
+            match stmt {
+                Statement::Let { ty, .. } => { top = self.alloc_slot(top, ty); deepest = deepest.min(top); }
+                Statement::Loop(_, body) | Statement::While(_, _, body) => deepest = deepest.min(self.frame_extent(body, top)),
+                Statement::For { start, body, .. } => {
+                    let inner = self.alloc_slot(self.alloc_slot(top, &start.ty), &start.ty);
+                    deepest = deepest.min(self.frame_extent(body, inner));
+                }
+                Statement::If(_, then_block, else_block) => {
+                    deepest = deepest.min(self.frame_extent(then_block, top));
+                    if let Some(else_block) = else_block { deepest = deepest.min(self.frame_extent(else_block, top)); }
+                }
+                _ => {}
+            }
+        }
+        deepest
+    }
+
+    pub fn compile(&mut self, program: &Program<Expr>) -> Result<Vec<u8>, Vec<Diagnostic>> {
         self.code.clear(); self.current_offset = 0;
-        self.emit_u8(0xE9); self.emit_u16(0); 
+        self.structs = program.structs.iter().map(|def| (def.name.clone(), def.size as usize)).collect();
 
+        // Constants go into `rodata`, initialised globals into `data`, and the
+        // others are only given an offset past it. Anything wider than a byte is
+        // word aligned.
+        let mut data = Vec::new();
+        let mut bss = Vec::new();
+        // Initializer words holding a string address, by position in their area and string offset
+        let (mut rodata_strings, mut data_strings) = (Vec::new(), Vec::new());
+        for global in &program.globals {
+            let fixed = global.attributes.iter().find_map(|attr| match attr { Attribute::Address(addr) => Some(*addr), _ => None });
+            if let Some(addr) = fixed {
+                self.globals.insert(global.name.clone(), addr);
+            } else if global.constant {
+                let (mut bytes, mut strings) = (Vec::new(), Vec::new());
+                self.initializer_bytes(global.value.as_ref().expect("sema requires a value for constants"), &mut bytes, &mut strings);
+                if bytes.len() > 1 && self.rodata.len() % 2 == 1 { self.rodata.push(0); }
+                let offset = self.rodata.len();
+                self.constants.insert(global.name.clone(), offset as u16);
+                rodata_strings.extend(strings.into_iter().map(|(at, string)| (offset + at, string)));
+                self.rodata.extend(bytes);
+            } else if !global.attributes.iter().any(|attr| matches!(attr, Attribute::Port(_))) {
+                match &global.value {
+                    Some(value) => data.push((global, value)),
+                    None => bss.push(global),
+                }
+            }
+        }
+        let mut placed = Vec::new();
+        let mut image_data = Vec::new();
+        for (global, value) in data {
+            let size = self.size_of(&global.ty);
+            if size > 1 && image_data.len() % 2 == 1 { image_data.push(0); }
+            placed.push((global, image_data.len()));
+            self.initializer_bytes(value, &mut image_data, &mut data_strings);
+        }
+        let mut end = image_data.len();
+        for global in bss {
+            let size = self.size_of(&global.ty);
+            if size > 1 && end % 2 == 1 { end += 1; }
+            placed.push((global, end));
+            end += size;
+        }
+        let bss_len = end - image_data.len();
+        for &(global, offset) in &placed { self.variables.insert(global.name.clone(), offset as u16); }
+
+        if bss_len > 0 {
+            self.emit_u8(0x31); self.emit_u8(0xC0); // xor ax, ax
+            self.emit_u8(0x8E); self.emit_u8(0xC0); // mov es, ax
+            self.emit_u8(0xBF); self.emit_address(Mem::Data(image_data.len() as u16)); // mov di, bss
+            self.emit_u8(0xB9); self.emit_u16(bss_len as u16); // mov cx, bss size
+            self.emit_u8(0xFC); // cld
+            self.emit_u8(0xF3); self.emit_u8(0xAA); // rep stosb
+        }
+        let handlers: Vec<_> = program.functions.iter()
+            .filter_map(|func| Some((func.interrupt()??, func.name.clone())))
+            .collect();
+        if !handlers.is_empty() {
+            self.emit_u8(0x9C); // pushf
+            self.emit_u8(0xFA); // cli
+            for (vector, name) in handlers {
+                let entry = vector as u16 * 4;
+                self.emit_u8(0xC7); self.emit_u8(0x06); self.emit_u16(entry); // mov word [vector*4], handler
+                self.address_fixups.push((self.current_offset, name));
+                self.emit_u16(0);
+                self.emit_u8(0xC7); self.emit_u8(0x06); self.emit_u16(entry + 2); self.emit_u16(0); // mov word [vector*4+2], 0
+            }
+            self.emit_u8(0x9D); // popf
+        }
+        self.emit_u8(0xE9); // jmp kernel_main
+        let entry_jump = self.current_offset;
+        self.emit_u16(0);
+
+        // The first function reaching past IMAGE_LIMIT, for the error below
+        let mut overflow = None;
         for func in &program.functions {
             self.functions.insert(func.name.clone(), self.current_offset);
             self.generate_function(func);
+            if overflow.is_none() && ORIGIN as usize + self.code.len() > IMAGE_LIMIT { overflow = Some(func.span); }
+        }
+
+        // A routine may call others, which are appended while emitting it.
+        let mut routine_offsets = Vec::new();
+        let mut next = 0;
+        while let Some(&routine) = self.routines.get(next) {
+            routine_offsets.push(self.current_offset);
+            self.emit_routine(routine);
+            next += 1;
+        }
+        if self.current_offset % 2 == 1 { self.emit_u8(0); }
+        let rodata_start = self.current_offset;
+        let rodata = std::mem::take(&mut self.rodata);
+        for &b in &rodata { self.emit_u8(b); }
+        if self.current_offset % 2 == 1 { self.emit_u8(0); }
+        let data_start = self.current_offset;
+        for &b in &image_data { self.emit_u8(b); }
+
+        // The image, with the bss after it, must stay clear of the stack. That
+        // also keeps every address below a valid 16-bit offset to patch in.
+        let image_end = ORIGIN as usize + self.code.len() + bss_len;
+        if image_end > IMAGE_LIMIT {
+            let data_origin = ORIGIN as usize + self.code.len() - image_data.len();
+            let global = placed.iter().find(|&&(global, offset)| data_origin + offset + self.size_of(&global.ty) > IMAGE_LIMIT);
+            let span = overflow
+                .or_else(|| global.map(|(global, _)| global.span))
+                .or_else(|| program.functions.iter().find(|f| f.name == "kernel_main").map(|f| f.span));
+            self.errors.push(Diagnostic::new(format!("image does not fit below {:#X}", IMAGE_LIMIT), span.unwrap_or_default())
+                .with_label(format!("the kernel and its globals would end at {:#X}, inside the boot stack below 0x7C00", image_end)));
+            return Err(std::mem::take(&mut self.errors));
         }
 
+        for (at, name) in std::mem::take(&mut self.call_fixups) {
+            let target = self.functions[&name];
+            self.patch_rel16(at, target);
+        }
+        for (at, name) in std::mem::take(&mut self.address_fixups) {
+            let target = self.functions[&name];
+            self.patch_u16(at, ORIGIN + target);
+        }
+        for (at, routine) in std::mem::take(&mut self.routine_fixups) {
+            let index = self.routines.iter().position(|&r| r == routine).unwrap();
+            self.patch_rel16(at, routine_offsets[index]);
+        }
+        for (at, offset) in std::mem::take(&mut self.rodata_fixups) {
+            self.patch_u16(at, ORIGIN + rodata_start + offset);
+        }
+        for (at, offset) in std::mem::take(&mut self.data_fixups) {
+            self.patch_u16(at, ORIGIN.wrapping_add(data_start).wrapping_add(offset));
+        }
+        for (area, strings) in [(rodata_start, rodata_strings), (data_start, data_strings)] {
+            for (at, offset) in strings {
+                self.patch_u16(area + at as u16, ORIGIN + rodata_start + offset);
+            }
+        }
         if let Some(&main_off) = self.functions.get("kernel_main") {
-            let rel = (main_off as i32 - 3) as u16;
-            self.code[1] = (rel & 0xFF) as u8; self.code[2] = (rel >> 8) as u8;
+            self.patch_rel16(entry_jump, main_off);
         }
-        self.code.clone()
+        if self.errors.is_empty() { Ok(self.code.clone()) } else { Err(std::mem::take(&mut self.errors)) }
+    }
+
+    /// Points the rel16 operand at `at` (ending at `at + 2`) to `target`.
+    fn patch_rel16(&mut self, at: u16, target: u16) {
+        self.patch_u16(at, target.wrapping_sub(at.wrapping_add(2)));
+    }
+
+    fn patch_u16(&mut self, at: u16, value: u16) {
+        self.code[at as usize] = (value & 0xFF) as u8; self.code[at as usize + 1] = (value >> 8) as u8;
     }
 
-    fn generate_function(&mut self, func: &Function) {
+    fn generate_function(&mut self, func: &Function<Expr>) {
+        let mut params = HashMap::new();
+        let mut offset = 4; // past the saved BP and the return address
+        for param in &func.params {
+            params.insert(param.name.clone(), (offset, param.ty.clone()));
+            offset += Self::slot_size(&param.ty);
+        }
+        self.scopes = vec![params];
+        self.frame_top = 0;
+
+        self.interrupt = func.interrupt().is_some();
+        if self.interrupt {
+            for push in [0x50, 0x51, 0x52, 0x53, 0x56, 0x57, 0x1E, 0x06] { self.emit_u8(push); } // push ax, cx, dx, bx, si, di, ds, es
+            self.emit_u8(0x31); self.emit_u8(0xC0); // xor ax, ax
+            self.emit_u8(0x8E); self.emit_u8(0xD8); // mov ds, ax
+        }
         self.emit_u8(0x55); // push bp
         self.emit_u8(0x89); self.emit_u8(0xE5); // mov bp, sp
-        for stmt in &func.body { self.generate_statement(stmt); }
+        let frame_size = -self.frame_extent(&func.body, 0);
+        if frame_size > 0x8000 {
+            self.errors.push(Diagnostic::new(format!("the locals of `{}` do not fit in a 16-bit stack frame", func.name), func.span)
+                .with_label(format!("they need {} bytes, [bp-disp] reaches 32 KiB", frame_size)));
+            return;
+        }
+        if frame_size > 0 {
+            self.emit_u8(0x81); self.emit_u8(0xEC); self.emit_u16(frame_size as u16); // sub sp, imm16
+        }
+        self.generate_block(&func.body);
+        self.emit_epilogue();
+    }
+
+    /// Generates `stmts` in a fresh scope; its locals' slots are free again afterwards.
+    fn generate_block(&mut self, stmts: &[Statement<Expr>]) {
+        let top = self.frame_top;
+        self.scopes.push(HashMap::new());
+        for stmt in stmts { self.generate_statement(stmt); }
+        self.scopes.pop();
+        self.frame_top = top;
+    }
+
+    /// Short `jcc` opcode that jumps when `op` holds after `cmp ax, bx`.
+    fn jcc_opcode(op: &Op, signed: bool) -> u8 {
+        match (op, signed) {
+            (Op::Eq, _) => 0x74, (Op::Ne, _) => 0x75,
+            (Op::Lt, false) => 0x72, (Op::Ge, false) => 0x73, (Op::Le, false) => 0x76, (Op::Gt, false) => 0x77,
+            (Op::Lt, true) => 0x7C, (Op::Ge, true) => 0x7D, (Op::Le, true) => 0x7E, (Op::Gt, true) => 0x7F,
+            _ => unreachable!("not a comparison"),
+        }
+    }
+
+    /// Evaluates both operands of a binary operator: left in AX, right in BX.
+    /// Far pointers keep their segments: left in DX, right in CX.
+    fn emit_operands(&mut self, left: &Expr, right: &Expr) {
+        self.emit_expression(left);
+        if left.ty.is_far() { self.emit_u8(0x52); } // push dx
+        self.emit_u8(0x50); // push ax
+        self.emit_expression(right);
+        self.emit_u8(0x89); self.emit_u8(0xC3); // mov bx, ax
+        if right.ty.is_far() { self.emit_u8(0x89); self.emit_u8(0xD1); } // mov cx, dx
+        self.emit_u8(0x58); // pop ax
+        if left.ty.is_far() { self.emit_u8(0x5A); } // pop dx
+    }
+
+    /// Compares two operands, leaving the flags for `jcc_opcode`. Far pointers
+    /// are equal when segment and offset both are, and ordered by offset.
+    fn emit_compare(&mut self, left: &Expr, op: &Op, right: &Expr) {
+        self.emit_operands(left, right);
+        self.emit_u8(0x39); self.emit_u8(0xD8); // cmp ax, bx
+        if left.ty.is_far() && matches!(op, Op::Eq | Op::Ne) {
+            self.emit_u8(0x75); self.emit_u8(2); // jne over the second cmp
+            self.emit_u8(0x39); self.emit_u8(0xCA); // cmp dx, cx
+        }
+    }
+
+    /// Emits a jump taken when `cond` is false and returns its rel16 operand for patching.
+    fn emit_jump_unless(&mut self, cond: &Expr) -> u16 {
+        match &cond.kind {
+            ExprKind::Binary(left, op, right) if op.is_comparison() => {
+                self.emit_compare(left, op, right);
+                self.emit_u8(Self::jcc_opcode(op, left.ty.is_signed())); self.emit_u8(3); // jcc over the jmp
+            }
+            _ => {
+                self.emit_expression(cond);
+                self.emit_u8(0x85); self.emit_u8(0xC0); // test ax, ax
+                self.emit_u8(0x75); self.emit_u8(3); // jnz over the jmp
+            }
+        }
+        self.emit_jmp_forward()
+    }
+
+    /// Emits `jmp rel16` with a placeholder target and returns the operand's offset.
+    fn emit_jmp_forward(&mut self) -> u16 {
+        self.emit_u8(0xE9);
+        let at = self.current_offset;
+        self.emit_u16(0);
+        at
+    }
+
+    fn emit_jmp_back(&mut self, target: u16) {
+        self.emit_u8(0xE9);
+        let at = self.current_offset;
+        self.emit_u16(0);
+        self.patch_rel16(at, target);
+    }
+
+    /// Generates a loop body with its own `break`/`continue` context.
+    fn generate_loop_body(&mut self, label: &Option<String>, body: &[Statement<Expr>]) -> LoopContext {
+        self.loops.push(LoopContext { label: label.clone(), breaks: Vec::new(), continues: Vec::new() });
+        self.generate_block(body);
+        self.loops.pop().unwrap()
+    }
+
+    fn patch_all(&mut self, fixups: &[u16], target: u16) {
+        for &at in fixups { self.patch_rel16(at, target); }
+    }
+
+    /// Emits the jump for `break` / `continue`, recording it in the targeted loop.
+    fn generate_loop_exit(&mut self, label: &Option<String>, is_break: bool) {
+        let index = match label {
+            Some(name) => self.loops.iter().rposition(|l| l.label.as_ref() == Some(name)),
+            None => self.loops.len().checked_sub(1),
+        };
+        let index = index.expect("sema rejects `break` and `continue` outside their loop");
+        let at = self.emit_jmp_forward();
+        let ctx = &mut self.loops[index];
+        if is_break { ctx.breaks.push(at); } else { ctx.continues.push(at); }
+    }
+
+    fn emit_epilogue(&mut self) {
         self.emit_u8(0x89); self.emit_u8(0xEC); // mov sp, bp
         self.emit_u8(0x5D); // pop bp
-        self.emit_u8(0xC3); // ret
+        if self.interrupt {
+            for pop in [0x07, 0x1F, 0x5F, 0x5E, 0x5B, 0x5A, 0x59, 0x58] { self.emit_u8(pop); } // pop es, ds, di, si, bx, dx, cx, ax
+            self.emit_u8(0xCF); // iret
+        } else {
+            self.emit_u8(0xC3); // ret
+        }
     }
 
-    fn generate_statement(&mut self, stmt: &Statement) {
+    fn generate_statement(&mut self, stmt: &Statement<Expr>) {
         match stmt {
-            Statement::Clear => {
+            Statement::Clear(_) => {
                 self.emit_u8(0xB8); self.emit_u16(0x0003); 
                 self.emit_u8(0xCD); self.emit_u8(0x10);    
             }
-            Statement::Newline => {
+            Statement::Newline(_) => {
                 for c in [0x0D, 0x0A] {
                     self.emit_u8(0xB4); self.emit_u8(0x0E); 
                     self.emit_u8(0xB0); self.emit_u8(c);    
                     self.emit_u8(0xCD); self.emit_u8(0x10); 
                 }
             }
-            Statement::Print(text, _color) => {
-                for c in text.chars() {
-                    self.emit_u8(0xB4); self.emit_u8(0x0E); 
-                    self.emit_u8(0xB0); self.emit_u8(c as u8); 
-                    self.emit_u8(0xCD); self.emit_u8(0x10); 
+            Statement::Print(text, color, _) => {
+                self.emit_color(color);
+                self.emit_u8(0xBA); self.emit_string_address(text); // mov dx, string
+                self.emit_call_routine(Routine::Print);
+            }
+            Statement::Printf(pieces, color, _) => {
+                // Values are pushed right to left like call arguments, so each is
+                // popped as its piece comes up; the routines leave BX alone.
+                for piece in pieces.iter().rev() {
+                    if let Format::Dec(value) | Format::Hex(value) = piece {
+                        self.emit_expression(value);
+                        self.emit_u8(0x50); // push ax
+                    }
+                }
+                self.emit_color(color);
+                for piece in pieces {
+                    match piece {
+                        Format::Text(text) => {
+                            self.emit_u8(0xBA); self.emit_string_address(text); // mov dx, string
+                            self.emit_call_routine(Routine::Print);
+                        }
+                        Format::Dec(value) => {
+                            self.emit_u8(0x58); // pop ax
+                            self.emit_call_routine(if value.ty.is_signed() { Routine::PrintSigned } else { Routine::PrintDec });
+                        }
+                        Format::Hex(value) => {
+                            self.emit_u8(0x58); // pop ax
+                            let digits = if self.size_of(&value.ty) == 1 { 2 } else { 4 };
+                            self.emit_u8(0xB9); self.emit_u16(digits); // mov cx, digits
+                            self.emit_call_routine(Routine::PrintHex);
+                        }
+                    }
+                }
+            }
+            Statement::Let { name, ty, value, .. } => {
+                self.frame_top = self.alloc_slot(self.frame_top, ty);
+                let disp = self.frame_top as i16;
+                match value {
+                    Some(value) => self.emit_initializer(Mem::Bp(disp), value),
+                    None if ty.is_aggregate() => {
+                        self.emit_u8(0x57); // push di
+                        self.emit_u8(0x8D); self.emit_modrm(7, Mem::Bp(disp)); // lea di, [bp+disp]
+                        self.emit_u8(0xB9); self.emit_u16(self.size_of(ty) as u16); // mov cx, size
+                        self.emit_u8(0x31); self.emit_u8(0xC0); // xor ax, ax
+                        self.emit_u8(0x16); self.emit_u8(0x07); // push ss; pop es
+                        self.emit_u8(0xFC); // cld
+                        self.emit_u8(0xF3); self.emit_u8(0xAA); // rep stosb
+                        self.emit_u8(0x5F); // pop di
+                    }
+                    None => {
+                        self.emit_u8(0x31); self.emit_u8(0xC0); // xor ax, ax
+                        self.emit_store(Mem::Bp(disp), ty);
+                    }
                 }
+                // Only now does the name refer to the new slot; the initialiser used the outer one.
+                self.scopes.last_mut().unwrap().insert(name.clone(), (disp, ty.clone()));
             }
-            Statement::Loop(body) => {
+            Statement::Loop(label, body) => {
                 let start = self.current_offset;
-                for s in body { self.generate_statement(s); }
-                self.emit_u8(0xE9);
-                let rel = (start as i32 - (self.current_offset as i32 + 3)) as u16;
-                self.emit_u16(rel);
+                let ctx = self.generate_loop_body(label, body);
+                self.patch_all(&ctx.continues, start);
+                self.emit_jmp_back(start);
+                self.patch_all(&ctx.breaks, self.current_offset);
             }
-            Statement::Asm(code) => {
-                if code.contains("hlt") { self.emit_u8(0xF4); }
+            Statement::While(label, cond, body) => {
+                let start = self.current_offset;
+                let to_end = self.emit_jump_unless(cond);
+                let ctx = self.generate_loop_body(label, body);
+                self.patch_all(&ctx.continues, start);
+                self.emit_jmp_back(start);
+                self.patch_all(&ctx.breaks, self.current_offset);
+                self.patch_rel16(to_end, self.current_offset);
             }
-            Statement::Assignment(target, value) => {
-                self.emit_expression(value); // Result in AX
+            Statement::For { label, var, start, end, body } => {
+                // The counter and the evaluated end bound get slots in a scope around the body.
+                let ty = start.ty.clone();
+                let top = self.frame_top;
+                let counter = self.alloc_slot(top, &ty);
+                let bound = self.alloc_slot(counter, &ty);
+                self.frame_top = bound;
+                let (counter, bound) = (counter as i16, bound as i16);
+                self.emit_expression(start);
+                self.emit_store(Mem::Bp(counter), &ty);
+                self.emit_expression(end);
+                self.emit_store(Mem::Bp(bound), &ty);
+                self.scopes.push(HashMap::from([(var.clone(), (counter, ty.clone()))]));
+
+                let check = self.current_offset;
+                self.emit_load(Mem::Bp(counter), &ty);
                 self.emit_u8(0x50); // push ax
-                self.emit_expression(target); // Address in AX
+                self.emit_load(Mem::Bp(bound), &ty);
                 self.emit_u8(0x89); self.emit_u8(0xC3); // mov bx, ax
                 self.emit_u8(0x58); // pop ax
-                self.emit_u8(0x89); self.emit_u8(0x07); // mov [bx], ax
+                self.emit_u8(0x39); self.emit_u8(0xD8); // cmp ax, bx
+                self.emit_u8(Self::jcc_opcode(&Op::Lt, ty.is_signed())); self.emit_u8(3); // jcc over the jmp
+                let to_end = self.emit_jmp_forward();
+
+                let ctx = self.generate_loop_body(label, body);
+                self.patch_all(&ctx.continues, self.current_offset);
+                self.emit_load(Mem::Bp(counter), &ty);
+                self.emit_u8(0x40); // inc ax
+                self.emit_store(Mem::Bp(counter), &ty);
+                self.emit_jmp_back(check);
+                self.patch_all(&ctx.breaks, self.current_offset);
+                self.patch_rel16(to_end, self.current_offset);
+
+                self.scopes.pop();
+                self.frame_top = top;
             }
+            Statement::Break(label, _) => self.generate_loop_exit(label, true),
+            Statement::Continue(label, _) => self.generate_loop_exit(label, false),
+            Statement::If(cond, then_block, else_block) => {
+                let to_else = self.emit_jump_unless(cond);
+                self.generate_block(then_block);
+                match else_block {
+                    Some(else_block) => {
+                        let to_end = self.emit_jmp_forward();
+                        self.patch_rel16(to_else, self.current_offset);
+                        self.generate_block(else_block);
+                        self.patch_rel16(to_end, self.current_offset);
+                    }
+                    None => self.patch_rel16(to_else, self.current_offset),
+                }
+            }
+            Statement::Asm(code) if code.contains("hlt") => { self.emit_u8(0xF4); }
+            Statement::Expression(expr) => self.emit_expression(expr),
+            Statement::Return(value, _) => {
+                if let Some(value) = value { self.emit_expression(value); } // Result in AX
+                self.emit_epilogue();
+            }
+            Statement::Assignment(target, value) => match &target.kind {
+                ExprKind::Local(name) => {
+                    self.emit_expression(value); // Result in AX
+                    let (disp, ty) = self.local(name);
+                    self.emit_store(Mem::Bp(disp), &ty);
+                }
+                ExprKind::Global(name) => {
+                    self.emit_expression(value); // Result in AX
+                    let mem = self.global_address(name);
+                    self.emit_store(mem, &target.ty);
+                }
+                ExprKind::Dereference(_) | ExprKind::Field(..) | ExprKind::Index(..) => {
+                    self.emit_expression(value); // Result in AX
+                    if value.ty.is_far() { self.emit_u8(0x52); } // push dx
+                    self.emit_u8(0x50); // push ax
+                    let mem = self.emit_place(target);
+                    self.emit_u8(0x58); // pop ax
+                    if value.ty.is_far() { self.emit_u8(0x5A); } // pop dx
+                    self.emit_store(mem, &target.ty);
+                }
+                _ => unreachable!("the parser only accepts variables, dereferences, fields and elements as assignment targets"),
+            },
             _ => {}
         }
     }
 
-    fn emit_expression(&mut self, expr: &Expression) {
-        match expr {
-            Expression::Number(n) => {
+    fn emit_expression(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Number(n) if expr.ty.is_far() => {
+                self.emit_u8(0xB8); self.emit_u16((*n & 0xF) as u16); // mov ax, offset
+                self.emit_u8(0xBA); self.emit_u16((*n >> 4) as u16); // mov dx, segment
+            }
+            ExprKind::Number(n) => {
                 self.emit_u8(0xB8); self.emit_u16(*n as u16);
             }
-            Expression::BinaryOp(left, op, right) => {
-                self.emit_expression(left);
-                self.emit_u8(0x50); 
-                self.emit_expression(right);
-                self.emit_u8(0x89); self.emit_u8(0xC3); 
-                self.emit_u8(0x58); 
+            ExprKind::Str(text) => {
+                self.emit_u8(0xB8); self.emit_string_address(text); // mov ax, string
+            }
+            ExprKind::Binary(left, op, right) if op.is_comparison() => {
+                self.emit_compare(left, op, right);
+                self.emit_u8(0xB8); self.emit_u16(1); // mov ax, 1 (flags untouched)
+                self.emit_u8(Self::jcc_opcode(op, left.ty.is_signed())); self.emit_u8(1); // jcc over the dec
+                self.emit_u8(0x48); // dec ax
+            }
+            ExprKind::Binary(left, op, right) if op.is_logical() => {
+                // Either side deciding the result jumps straight to the matching constant.
+                let mut decided = Vec::new();
+                for side in [left, right] {
+                    self.emit_expression(side);
+                    self.emit_u8(0x85); self.emit_u8(0xC0); // test ax, ax
+                    self.emit_u8(if matches!(op, Op::LogicalAnd) { 0x75 } else { 0x74 }); self.emit_u8(3); // jnz/jz over the jmp
+                    decided.push(self.emit_jmp_forward());
+                }
+                // Fell through both: && is true, || is false.
+                let (fallthrough, jumped) = if matches!(op, Op::LogicalAnd) { (1, 0) } else { (0, 1) };
+                self.emit_u8(0xB8); self.emit_u16(fallthrough); // mov ax, imm16
+                self.emit_u8(0xEB); self.emit_u8(3); // jmp short over the next mov
+                self.patch_all(&decided, self.current_offset);
+                self.emit_u8(0xB8); self.emit_u16(jumped); // mov ax, imm16
+            }
+            ExprKind::Binary(left, op, right) => {
+                let signed = left.ty.is_signed();
+                self.emit_operands(left, right);
                 match op {
-                    Op::Add => { self.emit_u8(0x01); self.emit_u8(0xD8); }
-                    Op::Sub => { self.emit_u8(0x29); self.emit_u8(0xD8); }
-                    _ => {}
+                    Op::Add => { self.emit_u8(0x01); self.emit_u8(0xD8); } // add ax, bx
+                    Op::Sub => { self.emit_u8(0x29); self.emit_u8(0xD8); } // sub ax, bx
+                    Op::And => { self.emit_u8(0x21); self.emit_u8(0xD8); } // and ax, bx
+                    Op::Or => { self.emit_u8(0x09); self.emit_u8(0xD8); } // or ax, bx
+                    Op::Xor => { self.emit_u8(0x31); self.emit_u8(0xD8); } // xor ax, bx
+                    Op::Mul => { self.emit_u8(0xF7); self.emit_u8(if signed { 0xEB } else { 0xE3 }); } // imul/mul bx
+                    Op::Div | Op::Rem => {
+                        if signed { self.emit_u8(0x99); } // cwd
+                        else { self.emit_u8(0x31); self.emit_u8(0xD2); } // xor dx, dx
+                        self.emit_u8(0xF7); self.emit_u8(if signed { 0xFB } else { 0xF3 }); // idiv/div bx
+                        if matches!(op, Op::Rem) { self.emit_u8(0x89); self.emit_u8(0xD0); } // mov ax, dx
+                    }
+                    Op::Shl | Op::Shr => {
+                        self.emit_u8(0x89); self.emit_u8(0xD9); // mov cx, bx
+                        let modrm = match op { Op::Shl => 0xE0, _ if signed => 0xF8, _ => 0xE8 };
+                        self.emit_u8(0xD3); self.emit_u8(modrm); // shl/sar/shr ax, cl
+                    }
+                    _ => unreachable!("comparisons and logical operators are handled above"),
                 }
+                self.emit_narrow(&expr.ty);
             }
-            Expression::Dereference(e) => {
-                self.emit_expression(e);
-                self.emit_u8(0x89); self.emit_u8(0xC3); 
-                self.emit_u8(0x8B); self.emit_u8(0x07); 
+            ExprKind::Unary(op, operand) => {
+                self.emit_expression(operand);
+                match op {
+                    UnaryOp::Neg => { self.emit_u8(0xF7); self.emit_u8(0xD8); } // neg ax
+                    UnaryOp::Not => { self.emit_u8(0xF7); self.emit_u8(0xD0); } // not ax
+                    UnaryOp::LogicalNot => {
+                        self.emit_u8(0xF7); self.emit_u8(0xD8); // neg ax (CF = ax != 0)
+                        self.emit_u8(0x19); self.emit_u8(0xC0); // sbb ax, ax
+                        self.emit_u8(0x40); // inc ax
+                    }
+                }
+                self.emit_narrow(&expr.ty);
             }
-            _ => {}
+            ExprKind::Cast(value) => {
+                self.emit_expression(value);
+                if expr.ty.is_far() && !value.ty.is_far() {
+                    if value.ty.is_pointer() { self.emit_u8(0x8C); self.emit_u8(0xDA); } // mov dx, ds
+                    else { self.emit_u8(0x31); self.emit_u8(0xD2); } // xor dx, dx
+                } else if expr.ty == Type::Bool && value.ty != Type::Bool {
+                    if value.ty.is_far() { self.emit_u8(0x09); self.emit_u8(0xD0); } // or ax, dx
+                    self.emit_u8(0xF7); self.emit_u8(0xD8); // neg ax (CF = ax != 0)
+                    self.emit_u8(0x19); self.emit_u8(0xC0); // sbb ax, ax
+                    self.emit_u8(0xF7); self.emit_u8(0xD8); // neg ax
+                } else {
+                    self.emit_narrow(&expr.ty);
+                }
+            }
+            ExprKind::Local(name) => {
+                let (disp, ty) = self.local(name);
+                self.emit_load(Mem::Bp(disp), &ty);
+            }
+            ExprKind::Global(name) => {
+                let mem = self.global_address(name);
+                self.emit_load(mem, &expr.ty);
+            }
+            ExprKind::Dereference(_) | ExprKind::Field(..) | ExprKind::Index(..) => {
+                let mem = self.emit_place(expr);
+                self.emit_load(mem, &expr.ty);
+            }
+            ExprKind::Array(_) | ExprKind::ByteStr(_) => unreachable!("aggregates are only initializers and places"),
+            ExprKind::AddressOf(place) => match &place.kind {
+                ExprKind::Local(name) => {
+                    let (disp, _) = self.local(name);
+                    self.emit_u8(0x8D); self.emit_modrm(0, Mem::Bp(disp)); // lea ax, [bp+disp]
+                }
+                ExprKind::Global(name) if !self.globals.contains_key(name) => {
+                    let mem = self.global_address(name);
+                    self.emit_u8(0xB8); self.emit_address(mem); // mov ax, addr
+                }
+                ExprKind::Global(name) => {
+                    let addr = self.globals[name];
+                    if expr.ty.is_far() {
+                        self.emit_u8(0xB8); self.emit_u16((addr & 0xF) as u16); // mov ax, offset
+                        self.emit_u8(0xBA); self.emit_u16((addr >> 4) as u16); // mov dx, segment
+                    } else {
+                        self.emit_u8(0xB8); self.emit_u16(addr as u16); // mov ax, addr
+                    }
+                }
+                ExprKind::ByteStr(bytes) => {
+                    let offset = self.intern(bytes.clone());
+                    self.emit_u8(0xB8); self.emit_address(Mem::Rodata(offset)); // mov ax, string
+                }
+                _ => unreachable!("sema only takes the address of variables and byte strings"),
+            },
+            ExprKind::Call(name, args) => {
+                // cdecl: right to left, far pointers pushing their segment first
+                for arg in args.iter().rev() {
+                    self.emit_expression(arg);
+                    if Self::slot_size(&arg.ty) > 2 { self.emit_extend_dx(&arg.ty); }
+                    for _ in 1..Self::slot_size(&arg.ty) / 2 {
+                        self.emit_u8(0x52); // push dx
+                    }
+                    self.emit_u8(0x50); // push ax
+                }
+                self.emit_u8(0xE8); // call rel16
+                self.call_fixups.push((self.current_offset, name.clone()));
+                self.emit_u16(0);
+                let cleanup: i16 = args.iter().map(|arg| Self::slot_size(&arg.ty)).sum();
+                if cleanup > 127 {
+                    self.emit_u8(0x81); self.emit_u8(0xC4); self.emit_u16(cleanup as u16); // add sp, imm16
+                } else if cleanup > 0 {
+                    self.emit_u8(0x83); self.emit_u8(0xC4); self.emit_u8(cleanup as u8); // add sp, imm8
+                }
+            }
+            ExprKind::Builtin(Builtin::Inb | Builtin::Inw | Builtin::Inl, args) => self.emit_port_in(&args[0], &expr.ty),
+            ExprKind::Builtin(Builtin::Outb | Builtin::Outw | Builtin::Outl, args) => self.emit_port_out(&args[0], &args[1]),
+            ExprKind::Builtin(builtin, args) => {
+                // Arguments are pushed like a call's; the expansion pops them where it needs them
+                for arg in args.iter().rev() {
+                    self.emit_expression(arg);
+                    self.emit_u8(0x50); // push ax
+                }
+                self.emit_builtin(*builtin);
+            }
+        }
+    }
+
+    fn emit_builtin(&mut self, builtin: Builtin) {
+        match builtin {
+            Builtin::ReadKey => {
+                self.emit_u8(0xB4); self.emit_u8(0x00); // mov ah, 00h
+                self.emit_u8(0xCD); self.emit_u8(0x16); // int 16h (AH = scancode, AL = ASCII)
+            }
+            Builtin::KeyAvailable => {
+                self.emit_u8(0xB4); self.emit_u8(0x01); // mov ah, 01h
+                self.emit_u8(0xCD); self.emit_u8(0x16); // int 16h (ZF = no key waiting)
+                self.emit_u8(0xB8); self.emit_u16(0); // mov ax, 0
+                self.emit_u8(0x74); self.emit_u8(0x01); // jz +1
+                self.emit_u8(0x40); // inc ax
+            }
+            Builtin::SetVideoMode => {
+                self.emit_u8(0x58); // pop ax (mode)
+                self.emit_u8(0x30); self.emit_u8(0xE4); // xor ah, ah
+                self.emit_u8(0xCD); self.emit_u8(0x10); // int 10h
+            }
+            Builtin::SetCursor => {
+                self.emit_u8(0x58); // pop ax (row)
+                self.emit_u8(0x5A); // pop dx (column)
+                self.emit_u8(0x88); self.emit_u8(0xC6); // mov dh, al
+                self.emit_u8(0xB4); self.emit_u8(0x02); // mov ah, 02h
+                self.emit_u8(0x30); self.emit_u8(0xFF); // xor bh, bh
+                self.emit_u8(0xCD); self.emit_u8(0x10); // int 10h
+            }
+            Builtin::GetCursor => {
+                self.emit_u8(0xB4); self.emit_u8(0x03); // mov ah, 03h
+                self.emit_u8(0x30); self.emit_u8(0xFF); // xor bh, bh
+                self.emit_u8(0xCD); self.emit_u8(0x10); // int 10h (DH = row, DL = column)
+                self.emit_u8(0x89); self.emit_u8(0xD0); // mov ax, dx
+            }
+            Builtin::HideCursor => {
+                self.emit_u8(0xB4); self.emit_u8(0x01); // mov ah, 01h
+                self.emit_u8(0xB9); self.emit_u16(0x2000); // mov cx, 2000h (start line bit 5: no cursor)
+                self.emit_u8(0xCD); self.emit_u8(0x10); // int 10h
+            }
+            Builtin::PutPixel => {
+                // Mode 13h: one byte per pixel at A000:(y * 320 + x)
+                self.emit_u8(0x5B); // pop bx (x)
+                self.emit_u8(0x58); // pop ax (y)
+                self.emit_u8(0xB9); self.emit_u16(320); // mov cx, 320
+                self.emit_u8(0xF7); self.emit_u8(0xE1); // mul cx
+                self.emit_u8(0x01); self.emit_u8(0xC3); // add bx, ax
+                self.emit_u8(0x58); // pop ax (colour)
+                self.emit_u8(0xB9); self.emit_u16(0xA000); // mov cx, 0A000h
+                self.emit_u8(0x8E); self.emit_u8(0xC1); // mov es, cx
+                self.emit_store(Mem::EsBx(0), &Type::U8);
+            }
+            _ => unreachable!("port I/O is expanded by emit_port_in/emit_port_out"),
         }
     }
 
-    fn emit_u8(&mut self, b: u8) { self.code.push(b); self.current_offset += 1; }
+    /// `in` of the width of `ty`: `in al, imm8` for a constant port below 256,
+    /// `in al, dx` otherwise.
+    fn emit_port_in(&mut self, port: &Expr, ty: &Type) {
+        let immediate = port.constant().filter(|&n| n < 256);
+        if immediate.is_none() {
+            self.emit_expression(port);
+            self.emit_u8(0x89); self.emit_u8(0xC2); // mov dx, ax
+        }
+        let word = if self.size_of(ty) == 1 { 0 } else { 1 };
+        match immediate {
+            Some(n) => { self.emit_u8(0xE4 | word); self.emit_u8(n as u8); } // in al/ax, imm8
+            None => self.emit_u8(0xEC | word), // in al/ax, dx
+        }
+        self.emit_narrow(ty);
+    }
+
+    /// `out` of the width of `value`, port encoded as in `emit_port_in`.
+    fn emit_port_out(&mut self, port: &Expr, value: &Expr) {
+        let immediate = port.constant().filter(|&n| n < 256);
+        if immediate.is_none() {
+            self.emit_expression(port);
+            self.emit_u8(0x50); // push ax
+        }
+        self.emit_expression(value);
+        if immediate.is_none() { self.emit_u8(0x5A); } // pop dx
+        let word = if self.size_of(&value.ty) == 1 { 0 } else { 1 };
+        match immediate {
+            Some(n) => { self.emit_u8(0xE6 | word); self.emit_u8(n as u8); } // out imm8, al/ax
+            None => self.emit_u8(0xEE | word), // out dx, al/ax
+        }
+    }
+
+    fn local(&self, name: &str) -> (i16, Type) {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).cloned()).expect("sema resolves every local")
+    }
+
+    /// Operand for global `name`, loading ES for a memory-mapped one above 64 KiB.
+    fn global_address(&mut self, name: &str) -> Mem {
+        if let Some(&offset) = self.variables.get(name) { return Mem::Data(offset); }
+        if let Some(&offset) = self.constants.get(name) { return Mem::Rodata(offset); }
+        let addr = self.globals[name];
+        if addr <= 0xFFFF { return Mem::Abs(addr as u16); }
+        self.emit_u8(0xBB); self.emit_u16((addr >> 4) as u16); // mov bx, segment
+        self.emit_u8(0x8E); self.emit_u8(0xC3); // mov es, bx
+        Mem::EsAbs((addr & 0xF) as u16)
+    }
+
+    /// Appends the bytes of a folded global initializer to `bytes`, element after
+    /// element for an array. A string address is left as a zero word, recorded in
+    /// `strings` with the offset of the string in `rodata`.
+    fn initializer_bytes(&mut self, value: &Expr, bytes: &mut Vec<u8>, strings: &mut Vec<(usize, u16)>) {
+        match &value.kind {
+            ExprKind::Array(elements) => for element in elements { self.initializer_bytes(element, bytes, strings) },
+            ExprKind::Str(text) => {
+                strings.push((bytes.len(), self.intern_string(text)));
+                bytes.extend([0, 0]);
+            }
+            _ => bytes.extend(self.constant_bytes(value.constant().expect("sema folds global initializers"), &value.ty)),
+        }
+    }
+
+    /// The bytes of the constant `value` stored as a `ty`, little-endian; far
+    /// pointers as offset then segment.
+    fn constant_bytes(&self, value: u64, ty: &Type) -> Vec<u8> {
+        let value = if ty.is_far() { (value & 0xF) | (value >> 4) << 16 } else { value };
+        value.to_le_bytes()[..self.size_of(ty)].to_vec()
+    }
+
+    /// Operand for a place: a local, a global, a dereference (the pointer is
+    /// evaluated into BX or ES:BX) or a field of one of them.
+    fn emit_place(&mut self, place: &Expr) -> Mem {
+        match &place.kind {
+            ExprKind::Local(name) => Mem::Bp(self.local(name).0),
+            ExprKind::Global(name) => self.global_address(name),
+            ExprKind::Dereference(pointer) => {
+                self.emit_expression(pointer);
+                self.emit_pointer_bx(&pointer.ty)
+            }
+            ExprKind::Field(base, offset) => self.emit_place(base).offset(*offset as i16),
+            ExprKind::Index(base, offset) => {
+                if let Some(offset) = offset.constant() { return self.emit_place(base).offset(offset as i16); }
+                self.emit_expression(offset);
+                self.emit_u8(0x50); // push ax
+                let mem = match self.emit_place(base) {
+                    mem @ Mem::Bp(_) => { self.emit_u8(0x8D); self.emit_modrm(3, mem); Mem::Bx(0) } // lea bx, [bp+disp]
+                    mem @ (Mem::Abs(_) | Mem::Data(_) | Mem::Rodata(_)) => { self.emit_u8(0xBB); self.emit_address(mem); Mem::Bx(0) } // mov bx, addr
+                    Mem::EsAbs(addr) => { self.emit_u8(0xBB); self.emit_u16(addr); Mem::EsBx(0) } // mov bx, addr
+                    mem @ (Mem::Bx(_) | Mem::EsBx(_)) => mem,
+                };
+                self.emit_u8(0x58); // pop ax
+                self.emit_u8(0x01); self.emit_u8(0xC3); // add bx, ax
+                mem
+            }
+            ExprKind::ByteStr(bytes) => Mem::Rodata(self.intern(bytes.clone())),
+            _ => unreachable!("sema only gives places a field, an element or an address"),
+        }
+    }
+
+    /// Stores the initializer `value` at `mem`, element by element for an array literal.
+    fn emit_initializer(&mut self, mem: Mem, value: &Expr) {
+        let ExprKind::Array(elements) = &value.kind else {
+            self.emit_expression(value);
+            self.emit_store(mem, &value.ty);
+            return;
+        };
+        for (i, element) in elements.iter().enumerate() {
+            let at = mem.offset((i * self.size_of(&element.ty)) as i16);
+            self.emit_initializer(at, element);
+        }
+    }
+
+    /// Moves the pointer in AX (DX:AX if far) into BX (ES:BX) and returns the operand it points at.
+    fn emit_pointer_bx(&mut self, ty: &Type) -> Mem {
+        self.emit_u8(0x89); self.emit_u8(0xC3); // mov bx, ax
+        if !ty.is_far() { return Mem::Bx(0); }
+        self.emit_u8(0x8E); self.emit_u8(0xC2); // mov es, dx
+        Mem::EsBx(0)
+    }
+
+    /// Re-extends AL into AX after arithmetic on an 8-bit `ty`; wider values are left alone.
+    fn emit_narrow(&mut self, ty: &Type) {
+        if matches!(ty, Type::U8 | Type::I8) { self.emit_extend_al(ty); }
+    }
+
+    /// Widens AL to AX according to the signedness of `ty`.
+    fn emit_extend_al(&mut self, ty: &Type) {
+        if ty.is_signed() { self.emit_u8(0x98); } // cbw
+        else { self.emit_u8(0x30); self.emit_u8(0xE4); } // xor ah, ah
+    }
+
+    /// Fills DX with the high word of AX widened according to `ty`.
+    fn emit_extend_dx(&mut self, ty: &Type) {
+        if ty.is_far() { return; } // DX already holds the segment
+        if ty.is_signed() { self.emit_u8(0x99); } // cwd
+        else { self.emit_u8(0x31); self.emit_u8(0xD2); } // xor dx, dx
+    }
+
+    /// Loads the `ty` at `mem` into AX, widening bytes. Far pointers also load
+    /// their segment into DX.
+    fn emit_load(&mut self, mem: Mem, ty: &Type) {
+        let byte = self.size_of(ty) == 1;
+        self.emit_segment(mem);
+        if let Mem::Abs(_) | Mem::EsAbs(_) | Mem::Data(_) | Mem::Rodata(_) = mem {
+            self.emit_u8(if byte { 0xA0 } else { 0xA1 }); self.emit_address(mem); // mov al/ax, [addr]
+        } else {
+            self.emit_u8(if byte { 0x8A } else { 0x8B }); self.emit_modrm(0, mem); // mov al/ax, [mem]
+        }
+        if byte { self.emit_extend_al(ty); }
+        if ty.is_far() {
+            self.emit_segment(mem);
+            self.emit_u8(0x8B); self.emit_modrm(2, mem.offset(2)); // mov dx, [mem+2]
+        }
+    }
+
+    /// Stores AX as a `ty` at `mem`; far pointers also store DX as their segment.
+    fn emit_store(&mut self, mem: Mem, ty: &Type) {
+        let byte = self.size_of(ty) == 1;
+        self.emit_segment(mem);
+        if let Mem::Abs(_) | Mem::EsAbs(_) | Mem::Data(_) | Mem::Rodata(_) = mem {
+            self.emit_u8(if byte { 0xA2 } else { 0xA3 }); self.emit_address(mem); // mov [addr], al/ax
+        } else {
+            self.emit_u8(if byte { 0x88 } else { 0x89 }); self.emit_modrm(0, mem); // mov [mem], al/ax
+        }
+        if byte { return; }
+        if self.size_of(ty) > 2 { self.emit_extend_dx(ty); }
+        for word in 1..self.size_of(ty) / 2 {
+            self.emit_segment(mem);
+            self.emit_u8(0x89); self.emit_modrm(2, mem.offset(2 * word as i16)); // mov [mem+2*word], dx
+        }
+    }
+
+    /// ES segment-override prefix for the far operands.
+    fn emit_segment(&mut self, mem: Mem) {
+        if let Mem::EsBx(_) | Mem::EsAbs(_) = mem { self.emit_u8(0x26); }
+    }
+
+    /// The address of an absolute operand; data area offsets are patched once it is placed.
+    fn emit_address(&mut self, mem: Mem) {
+        match mem {
+            Mem::Abs(addr) | Mem::EsAbs(addr) => self.emit_u16(addr),
+            Mem::Data(offset) => { self.data_fixups.push((self.current_offset, offset)); self.emit_u16(0); }
+            Mem::Rodata(offset) => { self.rodata_fixups.push((self.current_offset, offset)); self.emit_u16(0); }
+            Mem::Bp(_) | Mem::Bx(_) | Mem::EsBx(_) => unreachable!("not an absolute operand"),
+        }
+    }
+
+    /// ModR/M (plus displacement) for `mem` with `reg` in the reg field.
+    fn emit_modrm(&mut self, reg: u8, mem: Mem) {
+        let (rm, disp) = match mem {
+            Mem::Abs(_) | Mem::EsAbs(_) | Mem::Data(_) | Mem::Rodata(_) => { self.emit_u8(0x06 | (reg << 3)); self.emit_address(mem); return; }
+            Mem::Bp(disp) => (0b110, disp),
+            Mem::Bx(disp) | Mem::EsBx(disp) => (0b111, disp),
+        };
+        // [bp] has no displacement-free form: mod=00 with rm=110 means [addr].
+        if disp == 0 && rm != 0b110 {
+            self.emit_u8(rm | (reg << 3));
+        } else if (-128..=127).contains(&disp) {
+            self.emit_u8(0x40 | rm | (reg << 3)); self.emit_u8(disp as u8);
+        } else {
+            self.emit_u8(0x80 | rm | (reg << 3)); self.emit_u16(disp as u16);
+        }
+    }
+
+    /// Loads the attribute for the print routines into BX (BH = page 0).
+    fn emit_color(&mut self, color: &Option<Expr>) {
+        match color {
+            Some(color) => {
+                self.emit_expression(color);
+                self.emit_u8(0x89); self.emit_u8(0xC3); // mov bx, ax
+            }
+            None => { self.emit_u8(0xBB); self.emit_u16(0x0007); } // mov bx, 0x0007
+        }
+    }
+
+    /// Emits the absolute address of `text` in the string table, adding it if new.
+    fn emit_string_address(&mut self, text: &str) {
+        let offset = self.intern_string(text);
+        self.emit_address(Mem::Rodata(offset));
+    }
+
+    /// Offset of the NUL-terminated `text` in `rodata`.
+    fn intern_string(&mut self, text: &str) -> u16 {
+        self.intern(text.chars().map(|c| c as u8).chain([0]).collect())
+    }
+
+    /// Offset of `bytes` in `rodata`, adding them if no earlier literal had the same.
+    fn intern(&mut self, bytes: Vec<u8>) -> u16 {
+        if let Some(&offset) = self.strings.get(&bytes) { return offset; }
+        let offset = self.rodata.len() as u16;
+        self.rodata.extend(&bytes);
+        self.strings.insert(bytes, offset);
+        offset
+    }
+
+    /// Appends a byte. Past 64 KiB the offset wraps; `compile` rejects such an image.
+    fn emit_u8(&mut self, b: u8) { self.code.push(b); self.current_offset = self.current_offset.wrapping_add(1); }
     fn emit_u16(&mut self, w: u16) { self.emit_u8((w & 0xFF) as u8); self.emit_u8((w >> 8) as u8); }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::sema::Sema;
+
+    fn compile(source: &str) -> Result<Vec<u8>, Vec<Diagnostic>> {
+        let program = Parser::new(Lexer::new(source).tokenize().unwrap()).parse_program().unwrap();
+        let program = Sema::new(2).check(&program).unwrap();
+        Codegen::new().compile(&program)
+    }
+
+    fn code(body: &str) -> Vec<u8> {
+        compile(&format!("fn kernel_main() -> void {{\n{}\n}}\n", body)).unwrap()
+    }
+
+    fn count(code: &[u8], bytes: &[u8]) -> usize {
+        code.windows(bytes.len()).filter(|w| *w == bytes).count()
+    }
+
+    /// The `imm16` operands following each occurrence of `opcode`.
+    fn operands(code: &[u8], opcode: &[u8]) -> Vec<u16> {
+        (0..code.len() - opcode.len() - 1)
+            .filter(|&i| code[i..].starts_with(opcode))
+            .map(|i| u16::from_le_bytes([code[i + opcode.len()], code[i + opcode.len() + 1]]))
+            .collect()
+    }
+
+    /// The image from the absolute address `addr` on.
+    fn at(code: &[u8], addr: u16) -> &[u8] {
+        &code[(addr - ORIGIN) as usize..]
+    }
+
+    const PRINT_HEX: &[u8] = &[0x56, 0x89, 0xCE, 0x89, 0xC2, 0x88, 0xCD]; // push si; mov si, cx; mov dx, ax; mov ch, cl
+    const PRINT_SIGNED: &[u8] = &[0x85, 0xC0, 0x79, 0x09, 0x50, 0xB0, b'-']; // test ax, ax; jns; push ax; mov al, '-'
+
+    #[test]
+    fn strings_are_stored_once_after_the_code() {
+        let code = code(" print(\"hi\");\n print(\"hi\", 14);\n print(\"yo\");");
+        let strings = operands(&code, &[0xBA]); // mov dx, string
+        assert_eq!(strings.len(), 3);
+        assert_eq!(strings[0], strings[1]);
+        assert!(at(&code, strings[0]).starts_with(b"hi\0yo\0"));
+        assert_eq!(strings[2], strings[0] + 3);
+        assert_eq!(count(&code, &[0x89, 0xD6, 0xFC, 0xAC]), 1); // the print routine, emitted once
+    }
+
+    #[test]
+    fn images_reaching_the_boot_stack_are_an_error() {
+        let errors = compile(&format!("fn kernel_main() -> void {{\n{}}}\n", " print(\"x\");\n".repeat(3000))).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "image does not fit below 0x6C00");
+        let errors = compile(&format!("fn kernel_main() -> void {{\n{}}}\n", " if 1 == 1 { print(\"x\"); }\n".repeat(20000))).unwrap_err();
+        assert_eq!(errors[0].message, "image does not fit below 0x6C00");
+        assert_eq!(errors[0].span.line, 1);
+    }
+
+    #[test]
+    fn globals_are_placed_after_the_code_and_the_bss_is_zeroed() {
+        let code = compile("let COUNTER: u16 = 0x1234;\nlet LAST: u16;\nfn kernel_main() -> void {\n LAST = COUNTER;\n}\n").unwrap();
+        let [counter] = operands(&code, &[0xA1])[..] else { panic!("one `mov ax, [COUNTER]`") };
+        let [last] = operands(&code, &[0xA3])[..] else { panic!("one `mov [LAST], ax`") };
+        assert_eq!(at(&code, counter), [0x34, 0x12]);
+        // The bss starts where the image ends and is cleared before kernel_main
+        assert_eq!(last as usize, ORIGIN as usize + code.len());
+        assert!(code.starts_with(&[0x31, 0xC0, 0x8E, 0xC0, 0xBF])); // xor ax, ax; mov es, ax; mov di, bss
+        assert_eq!(operands(&code, &[0x8E, 0xC0, 0xBF])[0], last);
+        assert_eq!(operands(&code, &[0xBF, last as u8, (last >> 8) as u8, 0xB9])[0], 2); // mov cx, 2
+    }
+
+    #[test]
+    fn globals_count_towards_the_image() {
+        let source = "let buffer: [u8; 12000];\nlet more: [u8; 12000];\nfn kernel_main() -> void {\n}\n";
+        let errors = compile(source).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].message.as_str(), errors[0].span.line), ("image does not fit below 0x6C00", 2));
+        assert!(compile(&source.replace("12000", "11000")).is_ok());
+        assert!(compile("let buffer: [u8; 60000];\nfn kernel_main() -> void {\n}\n").is_err());
+
+        // The bss may run right up to the limit, 4 KiB below the boot stack at 0x7C00
+        let image = compile("let buffer: [u8; 2];\nfn kernel_main() -> void {\n}\n").unwrap();
+        let room = IMAGE_LIMIT - ORIGIN as usize - image.len();
+        assert_eq!(IMAGE_LIMIT, 0x6C00);
+        assert!(compile(&format!("let buffer: [u8; {}];\nfn kernel_main() -> void {{\n}}\n", room)).is_ok());
+        let errors = compile(&format!("let buffer: [u8; {}];\nfn kernel_main() -> void {{\n}}\n", room + 1)).unwrap_err();
+        assert_eq!(errors[0].label.as_deref(), Some("the kernel and its globals would end at 0x6C01, inside the boot stack below 0x7C00"));
+    }
+
+    #[test]
+    fn frames_past_32_kib_are_an_error() {
+        let errors = compile("fn kernel_main() -> void {\n let a: [u8; 40000];\n a[0] = 1;\n}\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "the locals of `kernel_main` do not fit in a 16-bit stack frame");
+        let errors = compile("fn kernel_main() -> void {\n let a: [u8; 20000];\n if 1 == 1 { let b: [u16; 7000]; }\n}\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn a_full_32_kib_frame_is_addressed_below_bp() {
+        let code = code(" let a: [u8; 20000];\n let b: [u8; 12768];\n a[19999] = 1;\n b[0] = 2;");
+        assert_eq!(count(&code, &[0x81, 0xEC, 0x00, 0x80]), 1); // sub sp, 8000h
+        assert_eq!(count(&code, &[0x8D, 0xBE, 0xE0, 0xB1, 0xB9, 0x20, 0x4E]), 1); // lea di, [bp-4E20h]; mov cx, 4E20h
+        assert_eq!(count(&code, &[0x88, 0x46, 0xFF]), 1); // mov [bp-1], al
+        assert_eq!(count(&code, &[0x88, 0x86, 0x00, 0x80]), 1); // mov [bp-8000h], al
+    }
+
+    #[test]
+    fn constant_indexes_into_a_lookup_table_are_absolute() {
+        let code = compile("const TABLE: [u16; 3] = [7, 8, 0x99];\nfn kernel_main() -> void {\n let x: u16 = TABLE[2];\n let y: u16 = TABLE[0];\n}\n").unwrap();
+        let [last, first] = operands(&code, &[0xA1])[..] else { panic!("two `mov ax, [TABLE+n]`") };
+        assert_eq!(last, first + 4);
+        assert_eq!(at(&code, first)[..6], [7, 0, 8, 0, 0x99, 0]);
+    }
+
+    #[test]
+    fn hex_digits_follow_the_width_of_the_value() {
+        let code = code(" print_hex(0xABu8);\n print_hex(0x1234);\n print_hex(cast<u16>(0xAB));");
+        assert_eq!(count(&code, &[0x58, 0xB9, 0x02, 0x00, 0xE8]), 1); // pop ax; mov cx, 2; call
+        assert_eq!(count(&code, &[0x58, 0xB9, 0x04, 0x00, 0xE8]), 2); // pop ax; mov cx, 4; call
+        assert_eq!(count(&code, PRINT_HEX), 1);
+    }
+
+    #[test]
+    fn decimal_output_follows_the_signedness_of_the_value() {
+        let unsigned = code(" print_dec(5);\n print_dec(0xFFFF);");
+        assert_eq!(count(&unsigned, PRINT_SIGNED), 0);
+        // The whole of AX is the dividend: DX is cleared before every `div`
+        assert_eq!(count(&unsigned, &[0x31, 0xD2, 0xF7, 0xF1]), 1); // xor dx, dx; div cx
+        let signed = code(" let x: i16 = -5;\n print_dec(x);\n printf(\"{}\", x);");
+        assert_eq!(count(&signed, PRINT_SIGNED), 1);
+    }
+
+    #[test]
+    fn printf_pops_its_values_in_order() {
+        let code = code(" printf(\"{x}-{d}\", 0x12, 0x34, 14);");
+        // Pushed right to left, then popped as each placeholder comes up
+        assert_eq!(count(&code, &[0xB8, 0x34, 0x00, 0x50, 0xB8, 0x12, 0x00, 0x50]), 1); // mov ax, 34h; push ax; mov ax, 12h; push ax
+        assert_eq!(count(&code, &[0x58, 0xB9, 0x04, 0x00, 0xE8]), 1); // pop ax; mov cx, 4; call print_hex
+        assert_eq!(count(&code, &[0x58, 0xE8]), 1); // pop ax; call print_dec
+    }
+
+    #[test]
+    fn far_pointers_are_split_into_segment_and_offset() {
+        let code = code(" let vga: far *u16 = 0xB8123;\n *vga = 0x0741;");
+        // mov ax, 0xB8123 & 0xF; mov dx, 0xB8123 >> 4
+        assert_eq!(count(&code, &[0xB8, 0x03, 0x00, 0xBA, 0x12, 0xB8]), 1);
+        // mov bx, ax; mov es, dx; pop ax; mov es:[bx], ax
+        assert_eq!(count(&code, &[0x89, 0xC3, 0x8E, 0xC2, 0x58, 0x26, 0x89, 0x07]), 1);
+
+        // A global above 64 KiB is reached through ES the same way
+        let code = compile("#[address(0xB8010)] let CELL: u16;\nfn kernel_main() -> void {\n CELL = 0x0742;\n}\n").unwrap();
+        // mov bx, 0xB801; mov es, bx; mov es:[0x0000], ax
+        assert_eq!(count(&code, &[0xBB, 0x01, 0xB8, 0x8E, 0xC3, 0x26, 0xA3, 0x00, 0x00]), 1);
+
+        // And a far initializer is stored offset first
+        let code = compile("let VGA: far *u16 = 0xB8123;\nfn kernel_main() -> void {\n}\n").unwrap();
+        assert!(code.ends_with(&[0x03, 0x00, 0x12, 0xB8]));
+    }
+
+    /// Where the `call rel16` or `jmp rel16` at `site` lands, as an offset into the image.
+    fn rel16_target(code: &[u8], site: usize) -> usize {
+        assert!(matches!(code[site], 0xE8 | 0xE9), "no call or jmp at {:#X}", site);
+        (site + 3).wrapping_add_signed(i16::from_le_bytes([code[site + 1], code[site + 2]]) as isize)
+    }
+
+    #[test]
+    fn calls_are_patched_to_their_function_in_either_order() {
+        let callee = "fn add(a: u16, b: u16, c: u16) -> u16 {\n return a + b + c;\n}\n";
+        let caller = "fn kernel_main() -> void {\n let x: u16 = add(1, 2, 3);\n}\n";
+        for (source, backwards) in [(format!("{}{}", callee, caller), true), (format!("{}{}", caller, callee), false)] {
+            let code = compile(&source).unwrap();
+            // Arguments are pushed last to first: mov ax, 3; push ax; ... mov ax, 1; push ax
+            let pushes = [0xB8, 0x03, 0x00, 0x50, 0xB8, 0x02, 0x00, 0x50, 0xB8, 0x01, 0x00, 0x50];
+            let site = code.windows(pushes.len()).position(|w| w == pushes).unwrap() + pushes.len();
+            let target = rel16_target(&code, site);
+            assert_eq!(target < site, backwards);
+            // add's prologue and its first parameter at [bp+4]
+            assert!(code[target..].starts_with(&[0x55, 0x89, 0xE5, 0x8B, 0x46, 0x04]));
+            // add sp, 6 for the three words pushed
+            assert!(code[site + 3..].starts_with(&[0x83, 0xC4, 0x06]));
+        }
+    }
+
+    #[test]
+    fn callers_pop_one_word_per_argument_word() {
+        let source = "fn tick() -> void {\n}\nfn one(c: u8) -> void {\n}\nfn poke(p: far *u8) -> void {\n}\n\
+            fn kernel_main() -> void {\n tick();\n one(65);\n poke(0xB8000);\n}\n";
+        let code = compile(source).unwrap();
+        let functions: Vec<usize> = (0..code.len() - 2).filter(|&i| code[i..].starts_with(&[0x55, 0x89, 0xE5])).collect();
+        let [tick, one, poke, _] = functions[..] else { panic!("four functions") };
+        let site = |target: usize| (0..code.len() - 2).find(|&i| code[i] == 0xE8 && rel16_target(&code, i) == target).unwrap();
+        // No arguments, nothing to pop: the next call's `mov ax, 65; push ax` follows
+        assert!(code[site(tick) + 3..].starts_with(&[0xB8, 0x41, 0x00, 0x50]));
+        assert!(code[site(one) + 3..].starts_with(&[0x83, 0xC4, 0x02])); // add sp, 2: a u8 takes a word
+        assert!(code[site(poke) + 3..].starts_with(&[0x83, 0xC4, 0x04])); // add sp, 4: far pointers take two
+    }
+
+    #[test]
+    fn comparisons_pick_jumps_by_signedness() {
+        let cases = [("<", 0x7C, 0x72), ("<=", 0x7E, 0x76), (">", 0x7F, 0x77), (">=", 0x7D, 0x73), ("==", 0x74, 0x74), ("!=", 0x75, 0x75)];
+        for (op, signed, unsigned) in cases {
+            for (ty, jcc) in [("i16", signed), ("u16", unsigned), ("i8", signed), ("u8", unsigned)] {
+                let code = code(&format!(" let a: {0} = 1;\n let b: {0} = 2;\n if a {1} b {{ a = 0; }}\n let c: bool = a {1} b;", ty, op));
+                // cmp ax, bx; jcc over the `jmp` to the else branch
+                assert_eq!(count(&code, &[0x39, 0xD8, jcc, 0x03, 0xE9]), 1, "`{}` on {}", op, ty);
+                // cmp ax, bx; mov ax, 1; jcc over the `dec ax`
+                assert_eq!(count(&code, &[0x39, 0xD8, 0xB8, 0x01, 0x00, jcc, 0x01, 0x48]), 1, "`{}` on {}", op, ty);
+            }
+        }
+    }
+
+    #[test]
+    fn break_and_continue_land_on_their_loop() {
+        let code = compile("let LAST: u16;\nfn kernel_main() -> void {\n rows: for y in 0..3 {\n  for x in 0..4 {\n\
+            if x == 1 { continue rows; }\n   if x == 2 { break rows; }\n   if y == 2 { break; }\n   continue;\n  }\n  LAST = y;\n }\n}\n").unwrap();
+        let find = |bytes: &[u8]| code.windows(bytes.len()).position(|w| w == bytes).unwrap();
+        // Each `if` jumps over its body when false: je +3; jmp +3; then the body's jmp
+        let jumps: Vec<usize> = (0..code.len() - 5).filter(|&i| code[i..].starts_with(&[0x74, 0x03, 0xE9, 0x03, 0x00, 0xE9])).map(|i| i + 5).collect();
+        let [continue_rows, break_rows, break_inner] = jumps[..] else { panic!("three `if` bodies") };
+        let continue_inner = break_inner + 3; // the plain `continue` right after the third `if`
+        let outer_increment = find(&[0x8B, 0x46, 0xFE, 0x40]); // mov ax, [bp-2] (y); inc ax
+        let inner_increment = find(&[0x8B, 0x46, 0xFA, 0x40]); // mov ax, [bp-6] (x); inc ax
+        let after_inner = find(&[0x8B, 0x46, 0xFE, 0xA3]); // mov ax, [bp-2]; mov [LAST], ax
+        let exit = find(&[0x89, 0xEC, 0x5D, 0xC3]);
+        assert_eq!(rel16_target(&code, continue_rows), outer_increment);
+        assert_eq!(rel16_target(&code, break_rows), exit);
+        assert_eq!(rel16_target(&code, break_inner), after_inner);
+        assert_eq!(rel16_target(&code, continue_inner), inner_increment);
+    }
+
+    #[test]
+    fn arithmetic_follows_the_signedness_of_its_operands() {
+        // Each operator runs after `mov bx, ax; pop ax` with the left operand in AX
+        let ops = |ty: &str| code(&format!(" let a: {0} = 7;\n let b: {0} = 2;\n let c: {0} = a * b;\n c = a / b;\n c = a % b;\n c = a << b;\n c = a >> b;", ty));
+        for ty in ["i16", "u16"] {
+            let code = ops(ty);
+            let signed = ty == "i16";
+            let pick = |s: &'static [u8], u: &'static [u8]| if signed { s } else { u };
+            let mul = pick(&[0x89, 0xC3, 0x58, 0xF7, 0xEB], &[0x89, 0xC3, 0x58, 0xF7, 0xE3]); // imul bx / mul bx
+            assert_eq!(count(&code, mul), 1, "{}", ty);
+            // cwd; idiv bx or xor dx, dx; div bx, for the quotient and then the remainder
+            let div = pick(&[0x89, 0xC3, 0x58, 0x99, 0xF7, 0xFB], &[0x89, 0xC3, 0x58, 0x31, 0xD2, 0xF7, 0xF3]);
+            assert_eq!(count(&code, div), 2, "{}", ty);
+            assert_eq!(count(&code, &[div, &[0x89, 0xD0]].concat()), 1, "{}", ty); // mov ax, dx
+            assert_eq!(count(&code, &[0x89, 0xD9, 0xD3, 0xE0]), 1, "{}", ty); // mov cx, bx; shl ax, cl
+            let shr = pick(&[0x89, 0xD9, 0xD3, 0xF8], &[0x89, 0xD9, 0xD3, 0xE8]); // sar ax, cl / shr ax, cl
+            assert_eq!(count(&code, shr), 1, "{}", ty);
+        }
+        // Bytes work in AX too and are re-extended after each operator
+        let code = ops("i8");
+        assert_eq!(count(&code, &[0xF7, 0xEB, 0x98]), 1); // imul bx; cbw
+        assert_eq!(count(&code, &[0xD3, 0xF8, 0x98]), 1); // sar ax, cl; cbw
+        let code = ops("u8");
+        assert_eq!(count(&code, &[0xF7, 0xE3, 0x30, 0xE4]), 1); // mul bx; xor ah, ah
+        assert_eq!(count(&code, &[0xD3, 0xE8, 0x30, 0xE4]), 1); // shr ax, cl; xor ah, ah
+    }
+
+    #[test]
+    fn struct_fields_are_stored_at_their_offsets() {
+        let code = compile("struct Entry { tag: u8, value: u16, flag: u8 }\n#[packed]\nstruct Packed { tag: u8, value: u16, flag: u8 }\n\
+            fn kernel_main() -> void {\n let e: Entry;\n e.tag = 1; e.value = 0x1234; e.flag = 2;\n\
+            let p: Packed;\n p.tag = 1; p.value = 0x1234; p.flag = 2;\n}\n").unwrap();
+        assert_eq!(count(&code, &[0x81, 0xEC, 10, 0]), 1); // sub sp, 6 + 4
+        // Both are zeroed whole: lea di, [bp+disp]; mov cx, size
+        assert_eq!(count(&code, &[0x8D, 0x7E, -6i8 as u8, 0xB9, 6, 0]), 1);
+        assert_eq!(count(&code, &[0x8D, 0x7E, -10i8 as u8, 0xB9, 4, 0]), 1);
+        // Entry at [bp-6]: tag, value after a pad byte, flag; one byte or word store each
+        for (opcode, disp) in [(0x88, -6i8), (0x89, -4), (0x88, -2)] {
+            assert_eq!(count(&code, &[0x58, opcode, 0x46, disp as u8]), 1, "[bp{}]", disp);
+        }
+        // Packed at [bp-10]: the word right after the byte, then the flag
+        for (opcode, disp) in [(0x88, -10i8), (0x89, -9), (0x88, -7)] {
+            assert_eq!(count(&code, &[0x58, opcode, 0x46, disp as u8]), 1, "[bp{}]", disp);
+        }
+    }
+
+    #[test]
+    fn ports_below_256_are_immediates() {
+        let code = compile("#[port(0x60)] volatile let KEYBOARD: u8;\n#[port(0x3F8)] volatile let COM1: u8;\n#[port(0x40)] volatile let PIT: u16;\n\
+            fn kernel_main() -> void {\n let k: u8 = KEYBOARD;\n KEYBOARD = 0x20;\n let c: u8 = COM1;\n COM1 = 65;\n\
+            PIT = 0x1234;\n let t: u16 = PIT;\n outb(0x3F9, 1);\n let x: u8 = inb(0x21);\n}\n").unwrap();
+        assert_eq!(count(&code, &[0xE4, 0x60]), 1); // in al, 0x60
+        assert_eq!(count(&code, &[0xB8, 0x20, 0x00, 0xE6, 0x60]), 1); // mov ax, 0x20; out 0x60, al
+        assert_eq!(count(&code, &[0xB8, 0x34, 0x12, 0xE7, 0x40]), 1); // mov ax, 0x1234; out 0x40, ax
+        assert_eq!(count(&code, &[0xE5, 0x40]), 1); // in ax, 0x40
+        assert_eq!(count(&code, &[0xE4, 0x21]), 1); // in al, 0x21
+        // 0x3F8 and 0x3F9 need DX: mov ax, port; mov dx, ax; in al, dx
+        assert_eq!(count(&code, &[0xB8, 0xF8, 0x03, 0x89, 0xC2, 0xEC]), 1);
+        // mov ax, port; push ax; mov ax, value; pop dx; out dx, al
+        assert_eq!(count(&code, &[0xB8, 0xF8, 0x03, 0x50, 0xB8, 0x41, 0x00, 0x5A, 0xEE]), 1);
+        assert_eq!(count(&code, &[0xB8, 0xF9, 0x03, 0x50, 0xB8, 0x01, 0x00, 0x5A, 0xEE]), 1);
+    }
+
+    #[test]
+    fn strings_and_byte_strings_share_the_read_only_data() {
+        let code = compile("const SIGNATURE: [u8; 4] = b\"BRCK\";\nlet GREETING: *u8 = \"hey\";\nfn kernel_main() -> void {\n\
+            let s: *u8 = \"hey\";\n let t: *u8 = \"yo\";\n let c: u8 = b\"XYZ\"[1];\n let p: *[u8; 2] = &b\"QR\";\n let g: u8 = SIGNATURE[3];\n}\n").unwrap();
+        let [hey, yo, qr] = operands(&code, &[0xB8])[..] else { panic!("three `mov ax, address`") };
+        let [y, k] = operands(&code, &[0xA0])[..] else { panic!("two `mov al, [address]`") };
+        // Strings end in NUL, byte strings do not; the constant comes first
+        assert_eq!(at(&code, hey - 4)[..16], *b"BRCKhey\0yo\0XYZQR");
+        assert_eq!((yo, y, qr, k), (hey + 4, hey + 8, hey + 10, hey - 1));
+        // Right after the code, which ends with kernel_main's `ret`
+        assert_eq!(at(&code, hey - 5)[0], 0xC3);
+        // The global's initializer is patched with the same string's address
+        assert!(code.ends_with(&hey.to_le_bytes()));
+    }
+
+    #[test]
+    fn keyboard_builtins_use_int_16h() {
+        let code = code(" if key_available() {\n  let k: u16 = read_key();\n }");
+        // AH = 01h sets ZF when no key is waiting; `mov ax, 0` keeps the flags, so
+        // AX is 1 only when ZF is clear
+        assert_eq!(count(&code, &[0xB4, 0x01, 0xCD, 0x16, 0xB8, 0x00, 0x00, 0x74, 0x01, 0x40]), 1);
+        assert_eq!(count(&code, &[0x85, 0xC0, 0x75, 0x03]), 1); // test ax, ax; jnz into the body
+        // AH = 00h waits and returns the scancode in AH and ASCII in AL, stored as one word
+        assert_eq!(count(&code, &[0xB4, 0x00, 0xCD, 0x16, 0x89, 0x46, 0xFE]), 1);
+    }
+
+    #[test]
+    fn the_colour_reaches_bl() {
+        let code = code(" let c: u8 = 0x1F;\n print(\"hi\", 14);\n print(\"hi\");\n print(\"hi\", c);");
+        assert_eq!(count(&code, &[0xB8, 0x0E, 0x00, 0x89, 0xC3, 0xBA]), 1); // mov ax, 14; mov bx, ax; mov dx, string
+        assert_eq!(count(&code, &[0xBB, 0x07, 0x00, 0xBA]), 1); // mov bx, 7: light grey by default
+        // A byte colour is zero-extended first, so BH (the page for int 10h) is 0
+        assert_eq!(count(&code, &[0x8A, 0x46, 0xFF, 0x30, 0xE4, 0x89, 0xC3, 0xBA]), 1);
+
+        // Print hands each character to PutChar without touching BX: push si; mov si, dx;
+        // cld; lodsb; test al, al; jz .done; call PutChar
+        let print = rel16_target(&code, code.windows(4).position(|w| w == [0xBB, 0x07, 0x00, 0xBA]).unwrap() + 6);
+        assert!(code[print..].starts_with(&[0x56, 0x89, 0xD6, 0xFC, 0xAC, 0x84, 0xC0, 0x74, 0x05, 0xE8]));
+        // PutChar writes AL with the attribute in BL: push ax; mov ah, 09h; mov cx, 1; int 10h
+        let put_char = rel16_target(&code, print + 9);
+        let end = put_char + code[put_char..].iter().position(|&b| b == 0xC3).unwrap();
+        assert_eq!(count(&code[put_char..end], &[0x50, 0xB4, 0x09, 0xB9, 0x01, 0x00, 0xCD, 0x10]), 1);
+        assert_eq!(count(&code[put_char..end], &[0x89, 0xC3]) + count(&code[put_char..end], &[0xBB]), 0);
+    }
+
+    #[test]
+    fn parameters_sit_above_bp_and_locals_below() {
+        let code = compile("fn f(a: u8, b: u16, p: far *u8, c: u16) -> u16 {\n let x: u8 = a;\n let y: u16 = b;\n let q: far *u8 = p;\n\
+            if y == 1 { let z: u16 = c; }\n let w: u8 = x;\n return y;\n}\nfn kernel_main() -> void {\n}\n").unwrap();
+        // push bp; mov bp, sp; sub sp, 10: x, y, q, and z in the nested block
+        assert!(code[3..].starts_with(&[0x55, 0x89, 0xE5, 0x81, 0xEC, 0x0A, 0x00]));
+        let disp = |d: i8| d as u8;
+        // a at [bp+4] into x at [bp-1]; y is word aligned at [bp-4]
+        assert_eq!(count(&code, &[0x8A, 0x46, 4, 0x30, 0xE4, 0x88, 0x46, disp(-1)]), 1);
+        assert_eq!(count(&code, &[0x8B, 0x46, 6, 0x89, 0x46, disp(-4)]), 1);
+        // p takes two words, offset at [bp+8] and segment at [bp+10], and so does q
+        assert_eq!(count(&code, &[0x8B, 0x46, 8, 0x8B, 0x56, 10, 0x89, 0x46, disp(-8), 0x89, 0x56, disp(-6)]), 1);
+        // c comes after p at [bp+12]; z gets the slot below q
+        assert_eq!(count(&code, &[0x8B, 0x46, 12, 0x89, 0x46, disp(-10)]), 1);
+        // w is handed the byte below q again once z's block has closed
+        assert_eq!(count(&code, &[0x8A, 0x46, disp(-1), 0x30, 0xE4, 0x88, 0x46, disp(-9)]), 1);
+        // kernel_main has no locals and no `sub sp`
+        let main = rel16_target(&code, 0);
+        assert!(code[main..].starts_with(&[0x55, 0x89, 0xE5, 0x89, 0xEC, 0x5D, 0xC3]));
+    }
+
+    #[test]
+    fn handlers_are_installed_in_the_ivt_and_return_with_iret() {
+        let code = compile("let TICKS: u16;\n#[interrupt(vector = 8)]\nfn timer() -> void {\n TICKS = TICKS + 1;\n}\n\
+            #[interrupt(vector = 9)]\nfn keyboard() -> void {\n TICKS = 0;\n}\nfn kernel_main() -> void {\n}\n").unwrap();
+        // With interrupts off: mov word [vector*4], handler; mov word [vector*4+2], 0
+        let install = code.windows(2).position(|w| w == [0x9C, 0xFA]).unwrap() + 2; // pushf; cli
+        let [timer, keyboard] = [8u16, 9].map(|vector| {
+            let entry = &code[install + (vector as usize - 8) * 12..];
+            assert_eq!(entry[..4], [0xC7, 0x06, (vector * 4) as u8, 0x00]);
+            assert_eq!(entry[6..12], [0xC7, 0x06, (vector * 4 + 2) as u8, 0x00, 0x00, 0x00]);
+            u16::from_le_bytes([entry[4], entry[5]])
+        });
+        assert_eq!(code[install + 24..install + 26], [0x9D, 0xE9]); // popf, then jmp kernel_main
+
+        // Each handler saves what the body may use, points DS at 0 and returns with iret
+        let prologue = [0x50, 0x51, 0x52, 0x53, 0x56, 0x57, 0x1E, 0x06, 0x31, 0xC0, 0x8E, 0xD8, 0x55, 0x89, 0xE5];
+        let epilogue = [0x89, 0xEC, 0x5D, 0x07, 0x1F, 0x5F, 0x5E, 0x5B, 0x5A, 0x59, 0x58, 0xCF];
+        for handler in [timer, keyboard] {
+            assert!(at(&code, handler).starts_with(&prologue), "{:#X}", handler);
+        }
+        assert_eq!(keyboard as usize - timer as usize, prologue.len() + 15 + epilogue.len()); // timer's body is 15 bytes
+        assert!(at(&code, keyboard - epilogue.len() as u16).starts_with(&epilogue));
+        assert_eq!(count(&code, &epilogue), 2);
+    }
+}
@@ -0,0 +1,157 @@
+// Runtime support routines for the 16-bit backend
+//
+// Builtins that would be too large to expand at every use call one of these
+// instead. Each routine is emitted once, after the user's functions, and only
+// if something calls it. Routines document their own register interface; all
+// of them preserve BP, SI, DI and the segment registers like any function.
+// Sema keeps integers on this target to 16 bits, so the number routines only
+// ever see a value in AX.
+
+use super::Codegen;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Routine {
+    /// Prints the character in AL in attribute BL and advances the cursor; `\n`
+    /// starts a new line and other control characters only move the cursor.
+    /// Clobbers AX and CX.
+    PutChar,
+    /// Prints the NUL-terminated string at DS:DX in attribute BL. Clobbers AX and CX.
+    Print,
+    /// Prints AX as an unsigned decimal in attribute BL. Clobbers AX, CX and DX.
+    PrintDec,
+    /// Prints AX as a signed decimal in attribute BL. Clobbers AX, CX and DX.
+    PrintSigned,
+    /// Prints the low CX hex digits of AX in attribute BL. Clobbers AX, CX and DX.
+    PrintHex,
+}
+
+impl Codegen {
+    /// Emits `call routine`, scheduling the routine for emission.
+    pub(super) fn emit_call_routine(&mut self, routine: Routine) {
+        if !self.routines.contains(&routine) { self.routines.push(routine); }
+        self.emit_u8(0xE8); // call rel16
+        self.routine_fixups.push((self.current_offset, routine));
+        self.emit_u16(0);
+    }
+
+    pub(super) fn emit_routine(&mut self, routine: Routine) {
+        match routine {
+            Routine::PutChar => {
+                self.emit_u8(0x3C); self.emit_u8(0x0A); // cmp al, 0Ah
+                let glyph = self.emit_jcc_short(0x75); // jne .glyph
+                self.emit_u8(0xB8); self.emit_u16(0x0E0D); // mov ax, 0E0Dh
+                self.emit_u8(0xCD); self.emit_u8(0x10); // int 10h
+                self.emit_u8(0xB0); self.emit_u8(0x0A); // mov al, 0Ah
+                let teletype = self.emit_jcc_short(0xEB); // jmp .teletype
+                self.patch_rel8(glyph, self.current_offset);
+                self.emit_u8(0x3C); self.emit_u8(0x20); // cmp al, 20h
+                let control = self.emit_jcc_short(0x72); // jb .teletype
+                // AH=09h writes the character with its attribute but leaves the cursor;
+                // the teletype call then advances it, keeping the attribute just written.
+                self.emit_u8(0x50); // push ax
+                self.emit_u8(0xB4); self.emit_u8(0x09); // mov ah, 09h
+                self.emit_u8(0xB9); self.emit_u16(1); // mov cx, 1
+                self.emit_u8(0xCD); self.emit_u8(0x10); // int 10h
+                self.emit_u8(0x58); // pop ax
+                self.patch_rel8(teletype, self.current_offset);
+                self.patch_rel8(control, self.current_offset);
+                self.emit_u8(0xB4); self.emit_u8(0x0E); // mov ah, 0Eh
+                self.emit_u8(0xCD); self.emit_u8(0x10); // int 10h
+                self.emit_u8(0xC3); // ret
+            }
+            Routine::Print => {
+                self.emit_u8(0x56); // push si
+                self.emit_u8(0x89); self.emit_u8(0xD6); // mov si, dx
+                self.emit_u8(0xFC); // cld
+                let next = self.current_offset;
+                self.emit_u8(0xAC); // lodsb
+                self.emit_u8(0x84); self.emit_u8(0xC0); // test al, al
+                let done = self.emit_jcc_short(0x74); // jz .done
+                self.emit_call_routine(Routine::PutChar);
+                self.emit_jmp_short_back(next); // jmp .next
+                self.patch_rel8(done, self.current_offset);
+                self.emit_u8(0x5E); // pop si
+                self.emit_u8(0xC3); // ret
+            }
+            Routine::PrintDec => {
+                // Digits come out least significant first, so they go through the stack
+                self.emit_u8(0x56); // push si
+                self.emit_u8(0x31); self.emit_u8(0xF6); // xor si, si
+                self.emit_u8(0xB9); self.emit_u16(10); // mov cx, 10
+                let divide = self.current_offset;
+                self.emit_u8(0x31); self.emit_u8(0xD2); // xor dx, dx
+                self.emit_u8(0xF7); self.emit_u8(0xF1); // div cx
+                self.emit_u8(0x52); // push dx
+                self.emit_u8(0x46); // inc si
+                self.emit_u8(0x85); self.emit_u8(0xC0); // test ax, ax
+                let more = self.emit_jcc_short(0x75); // jnz .divide
+                self.patch_rel8(more, divide);
+                self.emit_digits();
+            }
+            Routine::PrintSigned => {
+                self.emit_u8(0x85); self.emit_u8(0xC0); // test ax, ax
+                let positive = self.emit_jcc_short(0x79); // jns .positive
+                self.emit_u8(0x50); // push ax
+                self.emit_u8(0xB0); self.emit_u8(b'-'); // mov al, '-'
+                self.emit_call_routine(Routine::PutChar);
+                self.emit_u8(0x58); // pop ax
+                self.emit_u8(0xF7); self.emit_u8(0xD8); // neg ax
+                self.patch_rel8(positive, self.current_offset);
+                self.emit_call_routine(Routine::PrintDec);
+                self.emit_u8(0xC3); // ret
+            }
+            Routine::PrintHex => {
+                self.emit_u8(0x56); // push si
+                self.emit_u8(0x89); self.emit_u8(0xCE); // mov si, cx
+                self.emit_u8(0x89); self.emit_u8(0xC2); // mov dx, ax
+                self.emit_u8(0x88); self.emit_u8(0xCD); // mov ch, cl
+                self.emit_u8(0xB1); self.emit_u8(4); // mov cl, 4
+                let split = self.current_offset;
+                self.emit_u8(0x89); self.emit_u8(0xD0); // mov ax, dx
+                self.emit_u8(0x25); self.emit_u16(0x000F); // and ax, 0Fh
+                self.emit_u8(0x50); // push ax
+                self.emit_u8(0xD3); self.emit_u8(0xEA); // shr dx, cl
+                self.emit_u8(0xFE); self.emit_u8(0xCD); // dec ch
+                let more = self.emit_jcc_short(0x75); // jnz .split
+                self.patch_rel8(more, split);
+                self.emit_digits();
+            }
+        }
+    }
+
+    /// Tail of PrintDec and PrintHex: prints the SI digit values on the stack,
+    /// most significant on top, then restores SI and returns.
+    fn emit_digits(&mut self) {
+        let next = self.current_offset;
+        self.emit_u8(0x58); // pop ax
+        self.emit_u8(0x04); self.emit_u8(b'0'); // add al, '0'
+        self.emit_u8(0x3C); self.emit_u8(b'9'); // cmp al, '9'
+        let digit = self.emit_jcc_short(0x76); // jbe .put
+        self.emit_u8(0x04); self.emit_u8(b'A' - b'9' - 1); // add al, 'A' - '9' - 1
+        self.patch_rel8(digit, self.current_offset);
+        self.emit_call_routine(Routine::PutChar);
+        self.emit_u8(0x4E); // dec si
+        let more = self.emit_jcc_short(0x75); // jnz .next
+        self.patch_rel8(more, next);
+        self.emit_u8(0x5E); // pop si
+        self.emit_u8(0xC3); // ret
+    }
+
+    /// Emits a short `jcc` (or `jmp` with 0xEB) with a placeholder target and returns its rel8 operand.
+    fn emit_jcc_short(&mut self, opcode: u8) -> u16 {
+        self.emit_u8(opcode);
+        let at = self.current_offset;
+        self.emit_u8(0);
+        at
+    }
+
+    fn emit_jmp_short_back(&mut self, target: u16) {
+        let at = self.emit_jcc_short(0xEB);
+        self.patch_rel8(at, target);
+    }
+
+    /// Points the rel8 operand at `at` to `target`.
+    fn patch_rel8(&mut self, at: u16, target: u16) {
+        self.code[at as usize] = target.wrapping_sub(at.wrapping_add(1)) as u8;
+    }
+}
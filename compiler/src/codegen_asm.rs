@@ -1,10 +1,35 @@
-use crate::ast::*;
+use crate::parser::*;
+use crate::sema::{Expr, ExprKind, Builtin};
 use std::collections::HashMap;
 
+/// Registers carrying the first six arguments (see the calling convention in codegen/mod.rs).
+const ARG_REGS: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+
+/// Caller-saved registers, RBX included, which an `#[interrupt]` handler saves
+/// itself since it may interrupt code that still needs them.
+const HANDLER_SAVED_REGS: [&str; 10] = ["rax", "rbx", "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11"];
+
+/// Code segment selector of the IDT gates: the loader's GDT must have its
+/// 64-bit kernel code segment at index 1.
+const CODE_SELECTOR: u16 = 0x08;
+
 pub struct AsmGenerator {
     output: String,
     locals: HashMap<String, i32>,
     current_stack_offset: i32,
+    // Enclosing loops: label, label id and the stack offset to restore when leaving them
+    loops: Vec<(Option<String>, usize, i32)>,
+    label_count: usize,
+    // Vector and name of every `#[interrupt(vector = N)]` handler
+    vectors: Vec<(u64, String)>,
+    // The function being generated is an interrupt handler; `error_code` if the
+    // CPU pushes one for its vector, which has to be dropped before `iretq`
+    interrupt: bool,
+    error_code: bool,
+    // Size and alignment of every struct, as laid out by sema
+    structs: HashMap<String, (u64, u64)>,
+    // Contents of every string (with its NUL) and byte string, labelled `str_<index>`
+    strings: Vec<Vec<u8>>,
 }
 
 impl AsmGenerator {
@@ -13,180 +38,837 @@ impl AsmGenerator {
             output: String::new(),
             locals: HashMap::new(),
             current_stack_offset: 0,
+            loops: Vec::new(),
+            label_count: 0,
+            vectors: Vec::new(),
+            interrupt: false,
+            error_code: false,
+            structs: HashMap::new(),
+            strings: Vec::new(),
         }
     }
 
-    pub fn generate(&mut self, program: &Program) -> String {
+    // Unique suffix for the local labels of one statement or expression
+    fn new_label_id(&mut self) -> usize {
+        self.label_count += 1;
+        self.label_count
+    }
+
+    pub fn generate(&mut self, program: &Program<Expr>) -> String {
         self.output.clear();
-        
+        self.structs = program.structs.iter().map(|def| (def.name.clone(), (def.size, def.align))).collect();
+
         // Add basic header
         self.output.push_str("bits 64\n");
         self.output.push_str("section .text\n");
         self.output.push_str("global kernel_main\n\n");
 
-        for item in &program.items {
-            match item {
-                TopLevelItem::GlobalVariable(global) => self.generate_global(global),
-                TopLevelItem::Function(func) => self.generate_function(func),
-            }
+        for global in &program.globals {
+            self.generate_global(global);
+        }
+        self.vectors = program.functions.iter()
+            .filter_map(|func| Some((func.interrupt()??, func.name.clone())))
+            .collect();
+        for func in &program.functions {
+            self.generate_function(func);
+        }
+        // Still in .text: the installer is code, its table goes to .data
+        if !self.vectors.is_empty() {
+            self.generate_idt();
         }
-        
+        self.generate_storage(&program.globals);
+        self.generate_strings();
+
         self.output.clone()
     }
 
-    fn generate_global(&mut self, global: &GlobalVariable) {
+    fn generate_global(&mut self, global: &Global<Expr>) {
         // Handle global variables
         // For #[address(addr)], we might treat them as constants/equ if they are pointers
-        
+
         let mut addr: Option<u64> = None;
         for attr in &global.attributes {
-            if let Attribute::Address(a) = attr {
-                addr = Some(*a);
+            match attr {
+                Attribute::Address(a) => addr = Some(*a),
+                Attribute::Port(_) => return, // reads and writes are `in`/`out`, there is no storage
+                Attribute::Interrupt(_) | Attribute::Packed => {}
             }
         }
 
         if let Some(address) = addr {
             // Define as a constant symbol
             self.output.push_str(&format!("{} equ {}\n", global.name, address));
-        } else {
-            // Fallback for non-address globals
-            self.output.push_str(&format!("; Global {} (dynamic allocation not fully supported in simple backend)\n", global.name));
         }
     }
 
-    fn generate_function(&mut self, func: &Function) {
+    // Globals with storage: initialised ones in .data, the rest in .bss
+    fn generate_storage(&mut self, globals: &[Global<Expr>]) {
+        let stored: Vec<_> = globals.iter()
+            .filter(|global| !global.attributes.iter().any(|attr| matches!(attr, Attribute::Address(_) | Attribute::Port(_))))
+            .collect();
+        let (rodata, stored): (Vec<_>, Vec<_>) = stored.into_iter().partition(|global| global.constant);
+        let (data, bss): (Vec<_>, Vec<_>) = stored.into_iter().partition(|global| global.value.is_some());
+        for (section, globals) in [("rodata", rodata), ("data", data)] {
+            if globals.is_empty() {
+                continue;
+            }
+            self.output.push_str(&format!("\nsection .{}\n", section));
+            for global in globals {
+                let value = global.value.as_ref().expect("sema requires initializers on consts");
+                self.output.push_str(&format!("    align {}\n{}:\n", self.layout(&global.ty).1, global.name));
+                self.generate_data(value);
+            }
+        }
+        if !bss.is_empty() {
+            self.output.push_str("\nsection .bss\n");
+            for global in bss {
+                let (size, align) = self.layout(&global.ty);
+                self.output.push_str(&format!("    alignb {}\n{}: resb {}\n", align, global.name, size));
+            }
+        }
+    }
+
+    // One data directive per scalar of a folded initializer
+    fn generate_data(&mut self, value: &Expr) {
+        match &value.kind {
+            ExprKind::Array(elements) => elements.iter().for_each(|element| self.generate_data(element)),
+            ExprKind::Str(text) => {
+                let label = self.intern_string(text);
+                self.output.push_str(&format!("    dq {}\n", label));
+            }
+            _ => {
+                let (directive, size) = Self::data_directive(&value.ty);
+                let value = value.constant().expect("sema folds global initializers");
+                let value = if size < 8 { value & ((1 << (size * 8)) - 1) } else { value };
+                self.output.push_str(&format!("    {} {:#x}\n", directive, value));
+            }
+        }
+    }
+
+    // Stores each scalar of an array literal into the local at [rbp+offset]
+    fn generate_initializer(&mut self, offset: i32, value: &Expr) {
+        match &value.kind {
+            ExprKind::Array(elements) => {
+                for (i, element) in elements.iter().enumerate() {
+                    self.generate_initializer(offset + (i as u64 * self.layout(&element.ty).0) as i32, element);
+                }
+            }
+            _ => {
+                self.generate_expression(value);
+                self.output.push_str("    mov rbx, rax\n");
+                self.store(&format!("rbp{:+}", offset), &value.ty);
+            }
+        }
+    }
+
+    // Label of `bytes` in the string table, adding them if new
+    fn intern(&mut self, bytes: Vec<u8>) -> String {
+        let index = match self.strings.iter().position(|s| *s == bytes) {
+            Some(index) => index,
+            None => { self.strings.push(bytes); self.strings.len() - 1 }
+        };
+        format!("str_{}", index)
+    }
+
+    fn intern_string(&mut self, text: &str) -> String {
+        self.intern(text.chars().map(|c| c as u8).chain([0]).collect())
+    }
+
+    fn generate_strings(&mut self) {
+        if self.strings.is_empty() {
+            return;
+        }
+        self.output.push_str("\nsection .rodata\n");
+        for (index, bytes) in self.strings.iter().enumerate() {
+            let bytes: Vec<_> = bytes.iter().map(|b| b.to_string()).collect();
+            if bytes.is_empty() {
+                self.output.push_str(&format!("str_{}:\n", index));
+            } else {
+                self.output.push_str(&format!("str_{}: db {}\n", index, bytes.join(", ")));
+            }
+        }
+    }
+
+    // Size and alignment of a `ty` in bytes
+    fn layout(&self, ty: &Type) -> (u64, u64) {
+        match ty {
+            Type::Struct(name) => self.structs[name],
+            Type::Array(element, len) => { let (size, align) = self.layout(element); (size * len, align) }
+            ty => { let (_, size) = Self::data_directive(ty); (size as u64, size as u64) }
+        }
+    }
+
+    // The NASM data directive for a `ty` and its size in bytes
+    fn data_directive(ty: &Type) -> (&'static str, u32) {
+        match ty {
+            Type::U8 | Type::I8 | Type::Bool => ("db", 1),
+            Type::U16 | Type::I16 => ("dw", 2),
+            Type::U32 | Type::I32 => ("dd", 4),
+            _ => ("dq", 8),
+        }
+    }
+
+    fn generate_function(&mut self, func: &Function<Expr>) {
         self.output.push_str(&format!("{}:\n", func.name));
-        
+
         // Reset local tracking for new function
         self.locals.clear();
         self.current_stack_offset = 0;
 
         // Prologue
+        self.interrupt = func.interrupt().is_some();
+        self.error_code = matches!(func.interrupt(), Some(Some(8 | 10..=14 | 17 | 21 | 29 | 30)));
+        if self.interrupt {
+            for reg in HANDLER_SAVED_REGS {
+                self.output.push_str(&format!("    push {}\n", reg));
+            }
+            self.output.push_str("    cld\n");
+        }
         self.output.push_str("    push rbp\n");
         self.output.push_str("    mov rbp, rsp\n");
 
+        // Spill register arguments so parameters live in the frame like locals.
+        // Stack arguments are already in memory above the return address.
+        for (i, param) in func.params.iter().enumerate() {
+            if let Some(reg) = ARG_REGS.get(i) {
+                self.output.push_str(&format!("    push {}\n", reg));
+                self.current_stack_offset -= 8;
+                self.locals.insert(param.name.clone(), self.current_stack_offset);
+            } else {
+                let offset = 16 + 8 * (i - ARG_REGS.len()) as i32;
+                self.locals.insert(param.name.clone(), offset);
+            }
+        }
+
+        if func.name == "kernel_main" && !self.vectors.is_empty() {
+            self.output.push_str("    call bedrock_install_idt\n");
+        }
+
         for stmt in &func.body {
             self.generate_statement(stmt);
         }
 
-        // Epilogue
+        self.epilogue();
+        self.output.push('\n');
+    }
+
+    fn epilogue(&mut self) {
         self.output.push_str("    mov rsp, rbp\n"); // Clean up stack
         self.output.push_str("    pop rbp\n");
+        if !self.interrupt {
+            self.output.push_str("    ret\n");
+            return;
+        }
+        for reg in HANDLER_SAVED_REGS.iter().rev() {
+            self.output.push_str(&format!("    pop {}\n", reg));
+        }
+        if self.error_code {
+            self.output.push_str("    add rsp, 8\n");
+        }
+        self.output.push_str("    iretq\n");
+    }
+
+    // A 256-gate IDT with the `#[interrupt(vector = N)]` handlers as interrupt
+    // gates, loaded by kernel_main on entry. It replaces the loader's IDT, and
+    // vectors without a handler are not present.
+    fn generate_idt(&mut self) {
+        self.output.push_str("bedrock_install_idt:\n");
+        self.output.push_str("    mov rdi, bedrock_idt\n");
+        for (vector, name) in std::mem::take(&mut self.vectors) {
+            let gate = vector * 16;
+            self.output.push_str(&format!("    mov rax, {}\n", name));
+            self.output.push_str(&format!("    mov [rdi + {}], ax\n", gate));
+            self.output.push_str(&format!("    mov word [rdi + {}], {:#x}\n", gate + 2, CODE_SELECTOR));
+            self.output.push_str(&format!("    mov word [rdi + {}], 0x8E00\n", gate + 4)); // present, DPL 0, interrupt gate
+            self.output.push_str("    shr rax, 16\n");
+            self.output.push_str(&format!("    mov [rdi + {}], ax\n", gate + 6));
+            self.output.push_str("    shr rax, 16\n");
+            self.output.push_str(&format!("    mov [rdi + {}], eax\n", gate + 8));
+        }
+        self.output.push_str("    lidt [bedrock_idtr]\n");
         self.output.push_str("    ret\n\n");
+        self.output.push_str("section .data\n");
+        self.output.push_str("align 16\n");
+        self.output.push_str("bedrock_idt:\n");
+        self.output.push_str("    times 256 * 16 db 0\n");
+        self.output.push_str("bedrock_idtr:\n");
+        self.output.push_str("    dw 256 * 16 - 1\n");
+        self.output.push_str("    dq bedrock_idt\n");
     }
 
-    fn generate_statement(&mut self, stmt: &Statement) {
+    // Statements of a nested block; its locals and their stack space go away at the end
+    fn generate_block(&mut self, stmts: &[Statement<Expr>]) {
+        let (saved_locals, saved_offset) = (self.locals.clone(), self.current_stack_offset);
+        for s in stmts {
+            self.generate_statement(s);
+        }
+        if self.current_stack_offset != saved_offset {
+            self.output.push_str(&format!("    lea rsp, [rbp{:+}]\n", saved_offset));
+        }
+        self.locals = saved_locals;
+        self.current_stack_offset = saved_offset;
+    }
+
+    fn generate_loop_body(&mut self, label: &Option<String>, id: usize, stmts: &[Statement<Expr>]) {
+        self.loops.push((label.clone(), id, self.current_stack_offset));
+        self.generate_block(stmts);
+        self.loops.pop();
+    }
+
+    fn generate_statement(&mut self, stmt: &Statement<Expr>) {
         match stmt {
+            Statement::Let { name, ty, value, .. } if ty.is_aggregate() => {
+                // Zeroed stack space rounded up to whole slots
+                let size = self.layout(ty).0.next_multiple_of(8);
+                self.output.push_str(&format!("    sub rsp, {}\n", size));
+                self.output.push_str("    mov rdi, rsp\n");
+                self.output.push_str(&format!("    mov ecx, {}\n", size));
+                self.output.push_str("    xor eax, eax\n");
+                self.output.push_str("    rep stosb\n");
+                self.current_stack_offset -= size as i32;
+                self.locals.insert(name.clone(), self.current_stack_offset);
+                self.output.push_str(&format!("    ; {} at [rbp{}]\n", name, self.current_stack_offset));
+                if let Some(value) = value {
+                    self.generate_initializer(self.current_stack_offset, value);
+                }
+            }
             Statement::Let { name, value, .. } => {
                 // 1. Evaluate expression to RAX
-                self.generate_expression(value);
-                
+                if let Some(value) = value {
+                    self.generate_expression(value);
+                } else {
+                    self.output.push_str("    xor eax, eax\n");
+                }
+
                 // 2. Push RAX (allocating local var)
                 self.output.push_str("    push rax\n");
-                
+
                 // 3. Track offset
                 self.current_stack_offset -= 8;
                 self.locals.insert(name.clone(), self.current_stack_offset);
-                
+
                 self.output.push_str(&format!("    ; variable {} at [rbp{}]\n", name, self.current_stack_offset));
             }
-            Statement::UnsafeBlock(stmts) => {
-                for s in stmts {
-                    self.generate_statement(s);
+            Statement::Loop(label, stmts) => {
+                let id = self.new_label_id();
+                self.output.push_str(&format!(".L_loop_{}:\n.L_cont_{}:\n", id, id));
+                self.generate_loop_body(label, id, stmts);
+                self.output.push_str(&format!("    jmp .L_loop_{}\n", id));
+                self.output.push_str(&format!(".L_end_{}:\n", id));
+            }
+            Statement::While(label, cond, stmts) => {
+                let id = self.new_label_id();
+                self.output.push_str(&format!(".L_loop_{}:\n.L_cont_{}:\n", id, id));
+                self.generate_jump_unless(cond, &format!(".L_end_{}", id));
+                self.generate_loop_body(label, id, stmts);
+                self.output.push_str(&format!("    jmp .L_loop_{}\n", id));
+                self.output.push_str(&format!(".L_end_{}:\n", id));
+            }
+            Statement::For { label, var, start, end, body } => {
+                // Counter and end bound live on the stack for the duration of the loop
+                let id = self.new_label_id();
+                let (saved_locals, saved_offset) = (self.locals.clone(), self.current_stack_offset);
+                let cc = if start.ty.is_signed() { "ge" } else { "ae" };
+                self.generate_expression(start);
+                self.output.push_str("    push rax\n");
+                self.generate_expression(end);
+                self.output.push_str("    push rax\n");
+                self.current_stack_offset -= 16;
+                let (counter, bound) = (self.current_stack_offset + 8, self.current_stack_offset);
+                self.locals.insert(var.clone(), counter);
+
+                self.output.push_str(&format!(".L_loop_{}:\n", id));
+                self.output.push_str(&format!("    mov rax, [rbp{}]\n", counter));
+                self.output.push_str(&format!("    cmp rax, [rbp{}]\n", bound));
+                self.output.push_str(&format!("    j{} .L_end_{}\n", cc, id));
+                self.generate_loop_body(label, id, body);
+                self.output.push_str(&format!(".L_cont_{}:\n", id));
+                self.output.push_str(&format!("    inc qword [rbp{}]\n", counter));
+                self.output.push_str(&format!("    jmp .L_loop_{}\n", id));
+                self.output.push_str(&format!(".L_end_{}:\n", id));
+                self.output.push_str(&format!("    lea rsp, [rbp{:+}]\n", saved_offset));
+                self.locals = saved_locals;
+                self.current_stack_offset = saved_offset;
+            }
+            Statement::Break(label, _) | Statement::Continue(label, _) => {
+                let target = match label {
+                    Some(name) => self.loops.iter().rev().find(|l| l.0.as_ref() == Some(name)),
+                    None => self.loops.last(),
+                };
+                let kind = if matches!(stmt, Statement::Break(..)) { "end" } else { "cont" };
+                let (id, offset) = target.map(|l| (l.1, l.2)).expect("sema rejects `break` and `continue` outside their loop");
+                if offset != self.current_stack_offset {
+                    self.output.push_str(&format!("    lea rsp, [rbp{:+}]\n", offset));
                 }
+                self.output.push_str(&format!("    jmp .L_{}_{}\n", kind, id));
             }
-            Statement::LoopBlock(stmts) => {
-                let label = format!(".L_loop_{}", self.output.len());
-                self.output.push_str(&format!("{}:\n", label));
-                for s in stmts {
-                    self.generate_statement(s);
+            Statement::If(cond, then_block, else_block) => {
+                let id = self.new_label_id();
+                self.generate_jump_unless(cond, &format!(".L_else_{}", id));
+                self.generate_block(then_block);
+                self.output.push_str(&format!("    jmp .L_endif_{}\n", id));
+                self.output.push_str(&format!(".L_else_{}:\n", id));
+                if let Some(else_block) = else_block {
+                    self.generate_block(else_block);
                 }
-                self.output.push_str(&format!("    jmp {}\n", label));
+                self.output.push_str(&format!(".L_endif_{}:\n", id));
             }
-            Statement::ExpressionStmt(expr) => {
+            Statement::Expression(expr) => {
                 self.generate_expression(expr);
             }
-            Statement::Assignment { target, value } => {
+            Statement::Return(value, _) => {
+                if let Some(value) = value {
+                    self.generate_expression(value); // Result in RAX
+                }
+                self.epilogue();
+            }
+            Statement::Asm(code) => {
+                self.output.push_str(&format!("    {}\n", code));
+            }
+            Statement::Assignment(target, value) => {
                 // target = value
                 // 1. Evaluate value -> RAX
                 self.generate_expression(value);
                 self.output.push_str("    push rax\n"); // Save value
 
                 // 2. Evaluate target address
-                match target {
-                    Expression::Dereference(inner) => {
-                        // *ptr = val
-                        // Evaluate ptr -> RAX
-                        self.generate_expression(inner);
+                match &target.kind {
+                    ExprKind::Local(name) => {
+                        self.output.push_str("    pop rax\n");
+                        self.output.push_str(&format!("    mov [rbp{:+}], rax\n", self.locals[name]));
+                    }
+                    ExprKind::Global(name) => {
+                        self.output.push_str("    pop rbx\n");
+                        self.store(name, &target.ty);
+                    }
+                    ExprKind::Dereference(_) | ExprKind::Field(..) | ExprKind::Index(..) => {
+                        // *ptr = val, ptr.field = val
+                        self.generate_address(target);
                         self.output.push_str("    pop rbx\n"); // Pop value into RBX
-                        
+
                         // RAX has address, RBX has value
-                        // We use generic 'mov [rax], bx' (assuming 16-bit for this example)
-                        // A real compiler would check the type of *ptr.
-                        // For kernel.br example: *VIDEO_MEM (u16) = ...
-                        self.output.push_str("    mov [rax], bx\n");
-                    }
-                    _ => {
-                        self.output.push_str("    ; Complex assignment not supported in this demo\n");
-                        self.output.push_str("    add rsp, 8\n"); // Cleanup pushed value if we don't use it
+                        self.store("rax", &target.ty);
                     }
+                    _ => unreachable!("the parser only accepts variables, dereferences, fields and indexes as assignment targets"),
                 }
             }
+            Statement::Clear(_) | Statement::Newline(_) | Statement::Print(..) | Statement::Printf(..) => {
+                unreachable!("sema rejects console builtins on the 64-bit target")
+            }
+        }
+    }
+
+    // Condition code suffix for `op`
+    fn condition(op: &Op, signed: bool) -> &'static str {
+        match (op, signed) {
+            (Op::Eq, _) => "e", (Op::Ne, _) => "ne",
+            (Op::Lt, false) => "b", (Op::Ge, false) => "ae", (Op::Le, false) => "be", (Op::Gt, false) => "a",
+            (Op::Lt, true) => "l", (Op::Ge, true) => "ge", (Op::Le, true) => "le", (Op::Gt, true) => "g",
+            _ => unreachable!("not a comparison"),
         }
     }
 
-    fn generate_expression(&mut self, expr: &Expression) {
-        match expr {
-            Expression::Integer(val) => {
+    fn negate(op: &Op) -> Op {
+        match op {
+            Op::Eq => Op::Ne, Op::Ne => Op::Eq, Op::Lt => Op::Ge, Op::Ge => Op::Lt, Op::Le => Op::Gt, Op::Gt => Op::Le,
+            _ => unreachable!("not a comparison"),
+        }
+    }
+
+    // Left operand in RAX, right in RBX
+    fn generate_operands(&mut self, left: &Expr, right: &Expr) {
+        self.generate_expression(left);
+        self.output.push_str("    push rax\n");
+        self.generate_expression(right);
+        self.output.push_str("    mov rbx, rax\n");
+        self.output.push_str("    pop rax\n");
+    }
+
+    fn generate_jump_unless(&mut self, cond: &Expr, label: &str) {
+        match &cond.kind {
+            ExprKind::Binary(left, op, right) if op.is_comparison() => {
+                self.generate_operands(left, right);
+                self.output.push_str("    cmp rax, rbx\n");
+                let cc = Self::condition(&Self::negate(op), left.ty.is_signed());
+                self.output.push_str(&format!("    j{} {}\n", cc, label));
+            }
+            _ => {
+                self.generate_expression(cond);
+                self.output.push_str("    test rax, rax\n");
+                self.output.push_str(&format!("    jz {}\n", label));
+            }
+        }
+    }
+
+    fn generate_expression(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Number(val) if expr.ty.is_signed() => {
+                self.output.push_str(&format!("    mov rax, {}\n", *val as i64));
+            }
+            ExprKind::Number(val) => {
                 self.output.push_str(&format!("    mov rax, {}\n", val));
             }
-            Expression::Identifier(name) => {
-                if let Some(offset) = self.locals.get(name) {
-                    // It is a local variable
-                    self.output.push_str(&format!("    mov rax, [rbp{}]\n", offset));
-                } else {
-                    // Assume it is a global (EQU or Label)
-                    self.output.push_str(&format!("    mov rax, {}\n", name));
-                }
+            ExprKind::Str(text) => {
+                let label = self.intern_string(text);
+                self.output.push_str(&format!("    mov rax, {}\n", label));
             }
-            Expression::Cast { value, .. } => {
-                // Casts are usually no-ops in asm or just truncation/extension
-                self.generate_expression(value);
+            ExprKind::Local(name) => {
+                self.load(&format!("rbp{:+}", self.locals[name]), &expr.ty);
+            }
+            ExprKind::Global(name) => {
+                // EQU or label
+                self.load(name, &expr.ty);
             }
-            Expression::Dereference(inner) => {
-                self.generate_expression(inner);
-                self.output.push_str("    mov rax, [rax]\n");
+            ExprKind::Dereference(_) | ExprKind::Field(..) | ExprKind::Index(..) => {
+                self.generate_address(expr);
+                self.load("rax", &expr.ty);
             }
-            Expression::BinaryOp { op, left, right } => {
+            ExprKind::AddressOf(place) => self.generate_address(place),
+            ExprKind::Array(_) | ExprKind::ByteStr(_) => unreachable!("aggregates are only initializers and places"),
+            ExprKind::Binary(left, op, right) if op.is_comparison() => {
+                self.generate_operands(left, right);
+                self.output.push_str("    cmp rax, rbx\n");
+                self.output.push_str(&format!("    set{} al\n", Self::condition(op, left.ty.is_signed())));
+                self.output.push_str("    movzx eax, al\n");
+            }
+            ExprKind::Binary(left, op, right) if op.is_logical() => {
+                // Short-circuit: the left side alone may decide the result
+                let id = self.new_label_id();
+                let decided = if matches!(op, Op::LogicalAnd) { "jz" } else { "jnz" };
                 self.generate_expression(left);
-                self.output.push_str("    push rax\n");
+                self.output.push_str("    test rax, rax\n");
+                self.output.push_str(&format!("    {} .L_logic_{}\n", decided, id));
                 self.generate_expression(right);
-                self.output.push_str("    mov rbx, rax\n");
-                self.output.push_str("    pop rax\n");
+                self.output.push_str("    test rax, rax\n");
+                self.output.push_str(&format!(".L_logic_{}:\n", id));
+                self.output.push_str("    setnz al\n");
+                self.output.push_str("    movzx eax, al\n");
+            }
+            ExprKind::Binary(left, op, right) => {
+                let signed = left.ty.is_signed();
+                self.generate_operands(left, right);
                 match op {
-                    BinaryOperator::BitwiseOr => {
-                        self.output.push_str("    or rax, rbx\n");
+                    Op::Add => self.output.push_str("    add rax, rbx\n"),
+                    Op::Sub => self.output.push_str("    sub rax, rbx\n"),
+                    Op::Or => self.output.push_str("    or rax, rbx\n"),
+                    Op::And => self.output.push_str("    and rax, rbx\n"),
+                    Op::Xor => self.output.push_str("    xor rax, rbx\n"),
+                    Op::Mul => self.output.push_str("    imul rax, rbx\n"),
+                    Op::Div | Op::Rem => {
+                        self.output.push_str(if signed { "    cqo\n" } else { "    xor edx, edx\n" });
+                        self.output.push_str(if signed { "    idiv rbx\n" } else { "    div rbx\n" });
+                        if matches!(op, Op::Rem) {
+                            self.output.push_str("    mov rax, rdx\n");
+                        }
                     }
+                    Op::Shl | Op::Shr => {
+                        self.output.push_str("    mov rcx, rbx\n");
+                        let insn = match op { Op::Shl => "shl", _ if signed => "sar", _ => "shr" };
+                        self.output.push_str(&format!("    {} rax, cl\n", insn));
+                    }
+                    _ => unreachable!("comparisons and logical operators are handled above"),
                 }
+                self.narrow(&expr.ty);
             }
-            Expression::FunctionCall { name, args } => {
-                // BedRock example: inb(cast<u16>(0x60))
-                if name == "inb" {
-                     if let Some(arg) = args.first() {
-                         self.generate_expression(arg);
-                         // in al, dx
-                         self.output.push_str("    mov dx, ax\n");
-                         self.output.push_str("    in al, dx\n");
-                         self.output.push_str("    and rax, 0xFF\n"); // clear upper bits
-                     }
+            ExprKind::Unary(op, operand) => {
+                self.generate_expression(operand);
+                match op {
+                    UnaryOp::Neg => self.output.push_str("    neg rax\n"),
+                    UnaryOp::Not => self.output.push_str("    not rax\n"),
+                    UnaryOp::LogicalNot => {
+                        self.output.push_str("    test rax, rax\n");
+                        self.output.push_str("    setz al\n");
+                        self.output.push_str("    movzx eax, al\n");
+                    }
+                }
+                self.narrow(&expr.ty);
+            }
+            ExprKind::Cast(value) => {
+                self.generate_expression(value);
+                if expr.ty == Type::Bool && value.ty != Type::Bool {
+                    self.output.push_str("    test rax, rax\n");
+                    self.output.push_str("    setnz al\n");
+                    self.output.push_str("    movzx eax, al\n");
                 } else {
-                    // Standard call
-                    self.output.push_str(&format!("    call {}\n", name));
+                    self.narrow(&expr.ty);
                 }
             }
-            Expression::Asm(code) => {
-                self.output.push_str(&format!("    {}\n", code));
+            ExprKind::Call(name, args) => {
+                // Evaluate right to left onto the stack, then pop the first six into
+                // their argument registers; the rest stay on the stack for the callee.
+                for arg in args.iter().rev() {
+                    self.generate_expression(arg);
+                    self.output.push_str("    push rax\n");
+                }
+                for reg in ARG_REGS.iter().take(args.len()) {
+                    self.output.push_str(&format!("    pop {}\n", reg));
+                }
+                self.output.push_str(&format!("    call {}\n", name));
+                if args.len() > ARG_REGS.len() {
+                    self.output.push_str(&format!("    add rsp, {}\n", 8 * (args.len() - ARG_REGS.len())));
+                }
+            }
+            ExprKind::Builtin(Builtin::Inb | Builtin::Inw | Builtin::Inl, args) => self.generate_port_in(&args[0], &expr.ty),
+            ExprKind::Builtin(Builtin::Outb | Builtin::Outw | Builtin::Outl, args) => self.generate_port_out(&args[0], &args[1]),
+            ExprKind::Builtin(builtin, args) => {
+                // First argument on top, so generate_builtin pops them in order
+                for arg in args.iter().rev() {
+                    self.generate_expression(arg);
+                    self.output.push_str("    push rax\n");
+                }
+                self.generate_builtin(*builtin);
+            }
+        }
+    }
+
+    // No BIOS outside real mode: the keyboard builtins poll the 8042 controller,
+    // whose status port 0x64 has bit 0 set while a byte waits at port 0x60, and
+    // the cursor builtins program the VGA CRTC (index port 0x3D4, data 0x3D5)
+    fn generate_builtin(&mut self, builtin: Builtin) {
+        match builtin {
+            Builtin::ReadKey => {
+                // Raw set 1 scancode in the high byte; without a keymap there is no ASCII
+                let id = self.new_label_id();
+                self.output.push_str(&format!(".L_key_{}:\n", id));
+                self.output.push_str("    in al, 0x64\n");
+                self.output.push_str("    test al, 1\n");
+                self.output.push_str(&format!("    jz .L_key_{}\n", id));
+                self.output.push_str("    xor eax, eax\n");
+                self.output.push_str("    in al, 0x60\n");
+                self.output.push_str("    shl eax, 8\n");
+            }
+            Builtin::KeyAvailable => {
+                self.output.push_str("    xor eax, eax\n");
+                self.output.push_str("    in al, 0x64\n");
+                self.output.push_str("    and eax, 1\n");
+            }
+            Builtin::SetCursor => {
+                // Cursor location registers 0Eh (high byte) and 0Fh (low byte) hold row * 80 + col
+                self.output.push_str("    pop rax\n");
+                self.output.push_str("    pop rcx\n");
+                self.output.push_str("    imul eax, eax, 80\n");
+                self.output.push_str("    lea ecx, [rax + rcx]\n");
+                self.output.push_str("    mov dx, 0x3D4\n");
+                self.output.push_str("    mov al, 0x0F\n");
+                self.output.push_str("    out dx, al\n");
+                self.output.push_str("    inc dx\n");
+                self.output.push_str("    mov al, cl\n");
+                self.output.push_str("    out dx, al\n");
+                self.output.push_str("    dec dx\n");
+                self.output.push_str("    mov al, 0x0E\n");
+                self.output.push_str("    out dx, al\n");
+                self.output.push_str("    inc dx\n");
+                self.output.push_str("    mov al, ch\n");
+                self.output.push_str("    out dx, al\n");
+            }
+            Builtin::GetCursor => {
+                self.output.push_str("    mov dx, 0x3D4\n");
+                self.output.push_str("    mov al, 0x0E\n");
+                self.output.push_str("    out dx, al\n");
+                self.output.push_str("    inc dx\n");
+                self.output.push_str("    in al, dx\n");
+                self.output.push_str("    mov cl, al\n");
+                self.output.push_str("    dec dx\n");
+                self.output.push_str("    mov al, 0x0F\n");
+                self.output.push_str("    out dx, al\n");
+                self.output.push_str("    inc dx\n");
+                self.output.push_str("    in al, dx\n");
+                self.output.push_str("    mov ah, cl\n");
+                self.output.push_str("    movzx eax, ax\n");
+                self.output.push_str("    mov ecx, 80\n");
+                self.output.push_str("    xor edx, edx\n");
+                self.output.push_str("    div ecx\n"); // EAX = row, EDX = column
+                self.output.push_str("    shl eax, 8\n");
+                self.output.push_str("    or eax, edx\n");
+            }
+            Builtin::HideCursor => {
+                // Cursor start register 0Ah, bit 5 disables the cursor
+                self.output.push_str("    mov dx, 0x3D4\n");
+                self.output.push_str("    mov al, 0x0A\n");
+                self.output.push_str("    out dx, al\n");
+                self.output.push_str("    inc dx\n");
+                self.output.push_str("    mov al, 0x20\n");
+                self.output.push_str("    out dx, al\n");
+            }
+            Builtin::PutPixel => {
+                // The mode 13h framebuffer at physical 0xA0000, assumed identity-mapped
+                self.output.push_str("    pop rcx\n");
+                self.output.push_str("    pop rax\n");
+                self.output.push_str("    imul eax, eax, 320\n");
+                self.output.push_str("    add eax, ecx\n");
+                self.output.push_str("    pop rcx\n");
+                self.output.push_str("    mov [rax + 0xA0000], cl\n");
+            }
+            Builtin::SetVideoMode => unreachable!("sema rejects set_video_mode on the 64-bit target"),
+            _ => unreachable!("port I/O is expanded by generate_port_in/generate_port_out"),
+        }
+    }
+
+    // Port operand of `in`/`out`: an immediate for a constant port below 256, DX otherwise
+    fn port_operand(port: &Expr) -> String {
+        match port.constant() {
+            Some(n) if n < 256 => format!("{:#x}", n),
+            _ => "dx".to_string(),
+        }
+    }
+
+    // Accumulator register of the width of `ty`
+    fn accumulator(ty: &Type) -> &'static str {
+        match ty { Type::U8 => "al", Type::U16 => "ax", _ => "eax" }
+    }
+
+    fn generate_port_in(&mut self, port: &Expr, ty: &Type) {
+        let operand = Self::port_operand(port);
+        if operand == "dx" {
+            self.generate_expression(port);
+            self.output.push_str("    mov edx, eax\n");
+        }
+        self.output.push_str(&format!("    in {}, {}\n", Self::accumulator(ty), operand));
+        self.narrow(ty);
+    }
+
+    fn generate_port_out(&mut self, port: &Expr, value: &Expr) {
+        let operand = Self::port_operand(port);
+        if operand == "dx" {
+            self.generate_expression(port);
+            self.output.push_str("    push rax\n");
+        }
+        self.generate_expression(value);
+        if operand == "dx" { self.output.push_str("    pop rdx\n"); }
+        self.output.push_str(&format!("    out {}, {}\n", operand, Self::accumulator(&value.ty)));
+    }
+
+    // Address of a place in RAX: a local, a global, a dereference or a field of one
+    fn generate_address(&mut self, place: &Expr) {
+        match &place.kind {
+            ExprKind::Local(name) => self.output.push_str(&format!("    lea rax, [rbp{:+}]\n", self.locals[name])),
+            ExprKind::Global(name) => self.output.push_str(&format!("    mov rax, {}\n", name)),
+            ExprKind::Dereference(pointer) => self.generate_expression(pointer),
+            ExprKind::Field(base, offset) => {
+                self.generate_address(base);
+                if *offset != 0 {
+                    self.output.push_str(&format!("    add rax, {}\n", offset));
+                }
+            }
+            ExprKind::Index(base, offset) => match offset.constant() {
+                Some(offset) => {
+                    self.generate_address(base);
+                    if offset != 0 {
+                        self.output.push_str(&format!("    add rax, {}\n", offset));
+                    }
+                }
+                None => {
+                    self.generate_expression(offset);
+                    self.output.push_str("    push rax\n");
+                    self.generate_address(base);
+                    self.output.push_str("    pop rbx\n");
+                    self.output.push_str("    add rax, rbx\n");
+                }
+            },
+            ExprKind::ByteStr(bytes) => {
+                let label = self.intern(bytes.clone());
+                self.output.push_str(&format!("    mov rax, {}\n", label));
+            }
+            _ => unreachable!("sema only gives places a field, an index or an address"),
+        }
+    }
+
+    // Loads the `ty` at [addr] into RAX, zero- or sign-extending it
+    fn load(&mut self, addr: &str, ty: &Type) {
+        let insn = match ty {
+            Type::U8 | Type::Bool => "movzx eax, byte",
+            Type::I8 => "movsx rax, byte",
+            Type::U16 => "movzx eax, word",
+            Type::I16 => "movsx rax, word",
+            Type::U32 => "mov eax, dword",
+            Type::I32 => "movsxd rax, dword",
+            _ => "mov rax, qword",
+        };
+        self.output.push_str(&format!("    {} [{}]\n", insn, addr));
+    }
+
+    // Stores RBX at [addr], only as many bytes as `ty` occupies
+    fn store(&mut self, addr: &str, ty: &Type) {
+        let reg = match ty {
+            Type::U8 | Type::I8 | Type::Bool => "bl",
+            Type::U16 | Type::I16 => "bx",
+            Type::U32 | Type::I32 => "ebx",
+            _ => "rbx",
+        };
+        self.output.push_str(&format!("    mov [{}], {}\n", addr, reg));
+    }
+
+    // Re-extends RAX from the width of `ty`, after arithmetic that may have carried past it
+    fn narrow(&mut self, ty: &Type) {
+        let insn = match ty {
+            Type::U8 => "movzx eax, al",
+            Type::I8 => "movsx rax, al",
+            Type::U16 => "movzx eax, ax",
+            Type::I16 => "movsx rax, ax",
+            Type::U32 => "mov eax, eax",
+            Type::I32 => "movsxd rax, eax",
+            _ => return,
+        };
+        self.output.push_str(&format!("    {}\n", insn));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::sema::Sema;
+
+    fn generate(source: &str) -> String {
+        let program = Parser::new(Lexer::new(source).tokenize().unwrap()).parse_program().unwrap();
+        let program = Sema::new(8).check(&program).unwrap();
+        AsmGenerator::new().generate(&program)
+    }
+
+    /// The section the line `line` of `asm` ends up in.
+    fn section_of(asm: &str, line: &str) -> String {
+        let at = asm.lines().position(|l| l == line).unwrap_or_else(|| panic!("no `{}` in\n{}", line, asm));
+        let header = asm.lines().take(at).filter(|l| l.starts_with("section ")).last().unwrap();
+        header.to_string()
+    }
+
+    #[test]
+    fn the_idt_installer_is_code() {
+        let asm = generate("let counter: u64 = 1;\nconst LIMIT: u16 = 10;\nlet scratch: u64;\n#[interrupt(vector = 32)]\nfn tick() -> void { counter = counter + 1; }\nfn kernel_main() -> void { scratch = counter; }\n");
+        assert_eq!(section_of(&asm, "bedrock_install_idt:"), "section .text");
+        assert_eq!(section_of(&asm, "    lidt [bedrock_idtr]"), "section .text");
+        assert_eq!(section_of(&asm, "bedrock_idt:"), "section .data");
+        assert_eq!(section_of(&asm, "counter:"), "section .data");
+        assert_eq!(section_of(&asm, "LIMIT:"), "section .rodata");
+    }
+
+    #[test]
+    fn locals_are_loaded_at_their_width() {
+        let asm = generate("fn kernel_main() -> void {\n let a: u8 = 1;\n let b: i16 = -2;\n let c: *u8 = 0xB8000;\n let d: u64 = cast<u64>(a) + cast<u64>(b) + *c;\n}\n");
+        assert!(asm.contains("    movzx eax, byte [rbp-8]\n"), "{}", asm);
+        assert!(asm.contains("    movsx rax, word [rbp-16]\n"), "{}", asm);
+        assert!(asm.contains("    mov rax, qword [rbp-24]\n"), "{}", asm);
+    }
+
+    #[test]
+    fn handlers_save_every_register_their_body_writes() {
+        let source = "#[port(0x20)] volatile let PIC_COMMAND: u8;\n#[port(0x60)] volatile let KEYBOARD_DATA: u8;\nlet last: u64;\n\
+            #[interrupt(vector = 33)]\nfn keyboard() -> void {\n let key: u64 = KEYBOARD_DATA;\n last = key * 3 / 2 + (key << 1);\n PIC_COMMAND = 0x20;\n}\n\
+            fn kernel_main() -> void {\n}\n";
+        let asm = generate(source);
+        let handler: Vec<&str> = asm.lines().skip_while(|l| *l != "keyboard:").skip(1).take_while(|l| !l.is_empty()).collect();
+        let pushed: Vec<&str> = handler.iter().map_while(|l| l.strip_prefix("    push ")).collect();
+        let mut popped: Vec<&str> = handler.iter().rev().skip(1).map_while(|l| l.strip_prefix("    pop ")).filter(|r| *r != "rbp").collect();
+        popped.reverse();
+        assert_eq!(pushed.iter().rev().collect::<Vec<_>>(), popped.iter().collect::<Vec<_>>(), "{}", asm);
+        let body = &handler[pushed.len()..handler.len() - popped.len() - 1];
+        let mentioned = |reg: &str| body.iter().any(|l| l.split([' ', ',', '[', ']', '+', '-']).any(|word| word == reg));
+        for reg in ["rax", "rbx", "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"] {
+            if mentioned(reg) {
+                assert!(pushed.contains(&reg), "`{}` is used but not saved:\n{}", reg, asm);
             }
         }
+        assert!(mentioned("rbx") && pushed.contains(&"rbx"), "{}", asm);
     }
 }
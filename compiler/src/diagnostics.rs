@@ -0,0 +1,82 @@
+// Diagnostics: source spans and rustc-style error rendering
+
+/// Location of a token or node in the source. `start`/`end` are byte offsets,
+/// `line`/`col` are 1-based and point at `start`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span { pub start: usize, pub end: usize, pub line: usize, pub col: usize }
+
+impl Span {
+    /// Smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        if other.end <= self.start { return self; }
+        Span { end: other.end.max(self.end), ..self }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic { pub message: String, pub span: Span, pub label: Option<String> }
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        Diagnostic { message: message.into(), span, label: None }
+    }
+
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// Renders the error with the offending source line underlined:
+    ///
+    /// ```text
+    /// error: expected `;`, found `}`
+    ///  --> kernel.br:4:17
+    ///   |
+    /// 4 |     *vga = 0x2104
+    ///   |                 ^ expected `;`
+    /// ```
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let line_text = source.lines().nth(self.span.line.saturating_sub(1)).unwrap_or("");
+        let line_no = self.span.line.to_string();
+        let pad = " ".repeat(line_no.len());
+
+        // Underline at least one column, and never past the end of the line.
+        let col = self.span.col.max(1);
+        let spanned = source.get(self.span.start..self.span.end).unwrap_or("");
+        let spanned = spanned.split('\n').next().unwrap_or("");
+        let avail = line_text.chars().count().saturating_sub(col - 1);
+        let width = spanned.chars().count().min(avail).max(1);
+
+        let indent: String = line_text.chars().take(col - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+        let label = self.label.as_ref().map(|l| format!(" {}", l)).unwrap_or_default();
+
+        let mut out = format!("error: {}\n", self.message);
+        out.push_str(&format!("{}--> {}:{}:{}\n", pad, file, self.span.line, col));
+        out.push_str(&format!("{} |\n", pad));
+        out.push_str(&format!("{} | {}\n", line_no, line_text));
+        out.push_str(&format!("{} | {}{}{}\n", pad, indent, "^".repeat(width), label));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_line_with_an_underline() {
+        let source = "fn kernel_main() -> void {\n    *vga = 0x2104\n}\n";
+        let span = Span { start: 44, end: 45, line: 3, col: 1 };
+        let d = Diagnostic::new("expected `;`, found `}`", span).with_label("expected `;`");
+        assert_eq!(d.render("kernel.br", source), "error: expected `;`, found `}`\n --> kernel.br:3:1\n  |\n3 | }\n  | ^ expected `;`\n");
+    }
+
+    #[test]
+    fn underline_covers_the_span_but_not_past_the_line() {
+        let source = "let x: u8 = 300;\nlet y: u8 = 1;";
+        let d = Diagnostic::new("too big", Span { start: 12, end: 15, line: 1, col: 13 });
+        assert!(d.render("a.br", source).ends_with("1 | let x: u8 = 300;\n  |             ^^^\n"));
+        let d = Diagnostic::new("unterminated", Span { start: 12, end: 30, line: 1, col: 13 });
+        assert!(d.render("a.br", source).ends_with("  |             ^^^^\n"));
+    }
+}
@@ -1,110 +1,409 @@
+use crate::diagnostics::{Diagnostic, Span};
+use std::fmt;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
-    Fn, Let, Volatile, Unsafe, Loop, Asm, Cast,
-    Identifier(String), Number(u64), StringLiteral(String),
+    Fn, Let, Const, Volatile, Unsafe, Loop, While, For, In, Break, Continue, Asm, Cast, Return, If, Else, True, False, Far, Struct,
+    Identifier(String), Number(u64, Option<String>), StringLiteral(String), ByteStringLiteral(Vec<u8>), CharLiteral(u8),
     LParen, RParen, LBrace, RBrace, LBracket, RBracket,
-    Colon, SemiColon, Comma, Equal, Star, Arrow, Pipe, Hash, Plus, Minus,
-    LessThan, GreaterThan, Dot, EOF,
+    Colon, SemiColon, Comma, Equal, Star, Slash, Percent, Arrow, Pipe, PipePipe, Amp, AmpAmp, Caret, Tilde, Bang,
+    Hash, Plus, Minus, Shl, Shr,
+    LessThan, GreaterThan, LessEqual, GreaterEqual, EqualEqual, NotEqual, Dot, DotDot, Eof,
 }
 
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Token::Fn => "fn", Token::Let => "let", Token::Const => "const", Token::Volatile => "volatile", Token::Unsafe => "unsafe",
+            Token::Loop => "loop", Token::While => "while", Token::For => "for", Token::In => "in",
+            Token::Break => "break", Token::Continue => "continue", Token::Asm => "asm", Token::Cast => "cast", Token::Return => "return",
+            Token::If => "if", Token::Else => "else", Token::True => "true", Token::False => "false", Token::Far => "far", Token::Struct => "struct",
+            Token::Identifier(s) => return write!(f, "identifier `{}`", s),
+            Token::Number(n, _) => return write!(f, "number `{}`", n),
+            Token::StringLiteral(_) => return write!(f, "string literal"),
+            Token::ByteStringLiteral(_) => return write!(f, "byte string literal"),
+            Token::CharLiteral(_) => return write!(f, "character literal"),
+            Token::Eof => return write!(f, "end of file"),
+            Token::LParen => "(", Token::RParen => ")", Token::LBrace => "{", Token::RBrace => "}",
+            Token::LBracket => "[", Token::RBracket => "]", Token::Colon => ":", Token::SemiColon => ";",
+            Token::Comma => ",", Token::Equal => "=", Token::Star => "*", Token::Slash => "/", Token::Percent => "%",
+            Token::Arrow => "->", Token::Pipe => "|", Token::PipePipe => "||", Token::Amp => "&", Token::AmpAmp => "&&",
+            Token::Caret => "^", Token::Tilde => "~", Token::Bang => "!", Token::Hash => "#", Token::Plus => "+",
+            Token::Minus => "-", Token::Shl => "<<", Token::Shr => ">>",
+            Token::LessThan => "<", Token::GreaterThan => ">", Token::LessEqual => "<=", Token::GreaterEqual => ">=",
+            Token::EqualEqual => "==", Token::NotEqual => "!=", Token::Dot => ".", Token::DotDot => "..",
+        };
+        write!(f, "`{}`", s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedToken { pub token: Token, pub span: Span }
+
 pub struct Lexer {
     input: Vec<char>,
     pos: usize,
+    byte: usize,
+    line: usize,
+    col: usize,
 }
 
 impl Lexer {
     pub fn new(input: &str) -> Self {
-        Lexer { input: input.chars().collect(), pos: 0 }
+        Lexer { input: input.chars().collect(), pos: 0, byte: 0, line: 1, col: 1 }
     }
 
-    pub fn next_token(&mut self) -> Token {
+    /// Lexes the whole input, up to and including `Eof`. Lexing goes on past bad
+    /// characters, so every lexical error is reported at once.
+    pub fn tokenize(&mut self) -> Result<Vec<SpannedToken>, Vec<Diagnostic>> {
+        let mut tokens = Vec::new();
+        let mut errors = Vec::new();
+        loop {
+            match self.next_token() {
+                Ok(token) => {
+                    let done = token.token == Token::Eof;
+                    tokens.push(token);
+                    if done { break; }
+                }
+                Err(d) => errors.push(d),
+            }
+        }
+        if errors.is_empty() { Ok(tokens) } else { Err(errors) }
+    }
+
+    pub fn next_token(&mut self) -> Result<SpannedToken, Diagnostic> {
         self.skip_whitespace();
-        if self.pos >= self.input.len() { return Token::EOF; }
+        let start = self.here();
+        let token = self.read_token(start)?;
+        Ok(SpannedToken { token, span: Span { end: self.byte, ..start } })
+    }
+
+    fn read_token(&mut self, start: Span) -> Result<Token, Diagnostic> {
+        if self.pos >= self.input.len() { return Ok(Token::Eof); }
         let ch = self.input[self.pos];
 
-        if ch.is_alphabetic() || ch == '_' { return self.read_identifier(); }
-        if ch.is_digit(10) { return self.read_number(); }
-
-        match ch {
-            '(' => { self.pos += 1; Token::LParen }
-            ')' => { self.pos += 1; Token::RParen }
-            '{' => { self.pos += 1; Token::LBrace }
-            '}' => { self.pos += 1; Token::RBrace }
-            '[' => { self.pos += 1; Token::LBracket }
-            ']' => { self.pos += 1; Token::RBracket }
-            ':' => { self.pos += 1; Token::Colon }
-            ';' => { self.pos += 1; Token::SemiColon }
-            ',' => { self.pos += 1; Token::Comma }
-            '=' => { self.pos += 1; Token::Equal }
-            '*' => { self.pos += 1; Token::Star }
-            '|' => { self.pos += 1; Token::Pipe }
-            '#' => { self.pos += 1; Token::Hash }
-            '+' => { self.pos += 1; Token::Plus }
-            '.' => { self.pos += 1; Token::Dot }
-            '<' => { self.pos += 1; Token::LessThan }
-            '>' => { self.pos += 1; Token::GreaterThan }
-            '-' => {
-                if self.pos + 1 < self.input.len() && self.input[self.pos + 1] == '>' {
-                    self.pos += 2; Token::Arrow
-                } else { self.pos += 1; Token::Minus }
-            }
-            '"' => self.read_string(),
-            '/' => {
-                if self.pos + 1 < self.input.len() && self.input[self.pos + 1] == '/' {
-                    self.skip_comment(); self.next_token()
-                } else { panic!("Unexpected /"); }
+        if ch == 'b' && self.peek_at(1) == Some('"') { return self.read_byte_string(start); }
+        if ch.is_alphabetic() || ch == '_' { return Ok(self.read_identifier()); }
+        if ch.is_ascii_digit() { return self.read_number(start); }
+
+        let token = match ch {
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '{' => Token::LBrace,
+            '}' => Token::RBrace,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            ':' => Token::Colon,
+            ';' => Token::SemiColon,
+            ',' => Token::Comma,
+            '=' if self.peek_at(1) == Some('=') => { self.bump(); Token::EqualEqual }
+            '=' => Token::Equal,
+            '!' if self.peek_at(1) == Some('=') => { self.bump(); Token::NotEqual }
+            '!' => Token::Bang,
+            '*' => Token::Star,
+            '/' => Token::Slash,
+            '%' => Token::Percent,
+            '|' if self.peek_at(1) == Some('|') => { self.bump(); Token::PipePipe }
+            '|' => Token::Pipe,
+            '&' if self.peek_at(1) == Some('&') => { self.bump(); Token::AmpAmp }
+            '&' => Token::Amp,
+            '^' => Token::Caret,
+            '~' => Token::Tilde,
+            '#' => Token::Hash,
+            '+' => Token::Plus,
+            '.' if self.peek_at(1) == Some('.') => { self.bump(); Token::DotDot }
+            '.' => Token::Dot,
+            '<' if self.peek_at(1) == Some('=') => { self.bump(); Token::LessEqual }
+            '<' if self.peek_at(1) == Some('<') => { self.bump(); Token::Shl }
+            '<' => Token::LessThan,
+            '>' if self.peek_at(1) == Some('=') => { self.bump(); Token::GreaterEqual }
+            '>' if self.peek_at(1) == Some('>') => { self.bump(); Token::Shr }
+            '>' => Token::GreaterThan,
+            '-' if self.peek_at(1) == Some('>') => { self.bump(); Token::Arrow }
+            '-' => Token::Minus,
+            '"' => return self.read_string(start).map(Token::StringLiteral),
+            '\'' => return self.read_char(start),
+            _ => {
+                self.bump();
+                return Err(Diagnostic::new(format!("unexpected character `{}`", ch), self.span_from(start)));
             }
-            _ => panic!("Unexpected char {} at pos {}", ch, self.pos),
-        }
+        };
+        self.bump();
+        Ok(token)
+    }
+
+    fn here(&self) -> Span { Span { start: self.byte, end: self.byte, line: self.line, col: self.col } }
+    fn span_from(&self, start: Span) -> Span { Span { end: self.byte, ..start } }
+    fn peek_at(&self, n: usize) -> Option<char> { self.input.get(self.pos + n).copied() }
+
+    fn bump(&mut self) {
+        let ch = self.input[self.pos];
+        self.pos += 1;
+        self.byte += ch.len_utf8();
+        if ch == '\n' { self.line += 1; self.col = 1; } else { self.col += 1; }
     }
 
     fn skip_whitespace(&mut self) {
-        while self.pos < self.input.len() && self.input[self.pos].is_whitespace() { self.pos += 1; }
+        loop {
+            while self.pos < self.input.len() && self.input[self.pos].is_whitespace() { self.bump(); }
+            if self.peek_at(0) == Some('/') && self.peek_at(1) == Some('/') { self.skip_comment(); } else { break; }
+        }
     }
 
     fn skip_comment(&mut self) {
-        while self.pos < self.input.len() && self.input[self.pos] != '\n' { self.pos += 1; }
+        while self.pos < self.input.len() && self.input[self.pos] != '\n' { self.bump(); }
     }
 
     fn read_identifier(&mut self) -> Token {
         let start = self.pos;
         while self.pos < self.input.len() && (self.input[self.pos].is_alphanumeric() || self.input[self.pos] == '_') {
-            self.pos += 1;
+            self.bump();
         }
         let ident: String = self.input[start..self.pos].iter().collect();
         match ident.as_str() {
             "fn" => Token::Fn,
             "let" => Token::Let,
+            "const" => Token::Const,
             "volatile" => Token::Volatile,
             "unsafe" => Token::Unsafe,
             "loop" => Token::Loop,
+            "while" => Token::While,
+            "for" => Token::For,
+            "in" => Token::In,
+            "break" => Token::Break,
+            "continue" => Token::Continue,
             "asm" => Token::Asm,
             "cast" => Token::Cast,
+            "return" => Token::Return,
+            "if" => Token::If,
+            "else" => Token::Else,
+            "true" => Token::True,
+            "false" => Token::False,
+            "far" => Token::Far,
+            "struct" => Token::Struct,
             _ => Token::Identifier(ident),
         }
     }
 
-    fn read_number(&mut self) -> Token {
-        let start = self.pos;
-        // دعم الـ Hexadecimal 0x
-        if self.input[self.pos] == '0' && self.pos+1 < self.input.len() && self.input[self.pos+1].to_ascii_lowercase() == 'x' {
-            self.pos += 2;
-            let hex_start = self.pos;
-            while self.pos < self.input.len() && self.input[self.pos].is_digit(16) { self.pos += 1; }
-            let hex_str: String = self.input[hex_start..self.pos].iter().collect();
-            let num = u64::from_str_radix(&hex_str, 16).expect("Invalid hex number");
-            return Token::Number(num);
+    /// Reads a decimal, `0x` hex, `0b` binary or `0o` octal literal. Digits may be
+    /// separated by `_`, and an integer type may follow as a suffix (`0xFFu16`).
+    fn read_number(&mut self, start: Span) -> Result<Token, Diagnostic> {
+        let prefix = match self.peek_at(1).map(|c| c.to_ascii_lowercase()) {
+            Some(c @ ('x' | 'b' | 'o')) if self.input[self.pos] == '0' => Some(c),
+            _ => None,
+        };
+        let (radix, name) = match prefix {
+            Some('x') => (16, "a hexadecimal"),
+            Some('b') => (2, "a binary"),
+            Some('o') => (8, "an octal"),
+            _ => (10, "a decimal"),
+        };
+        if prefix.is_some() { self.bump(); self.bump(); }
+        // Any decimal digit belongs to the literal, so `0b102` is a bad digit rather than a suffix
+        let mut digits = String::new();
+        while let Some(c) = self.peek_at(0).filter(|&c| c == '_' || c.is_digit(radix.max(10))) {
+            if c == '_' {
+                self.bump();
+                continue;
+            }
+            if !c.is_digit(radix) {
+                let at = self.here();
+                self.bump();
+                return Err(Diagnostic::new(format!("invalid digit `{}` in {} literal", c, name), self.span_from(at))
+                    .with_label(format!("not a base {} digit", radix)));
+            }
+            digits.push(c);
+            self.bump();
+        }
+        if let (true, Some(prefix)) = (digits.is_empty(), prefix) {
+            return Err(Diagnostic::new(format!("missing digits after `0{}`", prefix), self.span_from(start)));
+        }
+        let suffix_start = self.here();
+        let mut suffix = String::new();
+        while let Some(c) = self.peek_at(0).filter(|c| c.is_alphanumeric() || *c == '_') {
+            suffix.push(c);
+            self.bump();
         }
-        while self.pos < self.input.len() && self.input[self.pos].is_digit(10) { self.pos += 1; }
-        Token::Number(self.input[start..self.pos].iter().collect::<String>().parse().unwrap())
+        let suffix = match suffix.as_str() {
+            "" => None,
+            "u8" | "u16" | "u32" | "u64" | "i8" | "i16" | "i32" | "i64" => Some(suffix),
+            _ => return Err(Diagnostic::new(format!("invalid suffix `{}` for a number literal", suffix), self.span_from(suffix_start))
+                .with_label("expected an integer type such as `u8` or `i16`")),
+        };
+        u64::from_str_radix(&digits, radix).map(|n| Token::Number(n, suffix))
+            .map_err(|_| Diagnostic::new("integer literal is too large", self.span_from(start))
+                .with_label("literals must fit in 64 bits"))
     }
 
-    fn read_string(&mut self) -> Token {
-        self.pos += 1; // skip "
-        let start = self.pos;
-        while self.pos < self.input.len() && self.input[self.pos] != '"' { self.pos += 1; }
-        let s = self.input[start..self.pos].iter().collect();
-        self.pos += 1; // skip "
-        Token::StringLiteral(s)
+    fn read_string(&mut self, start: Span) -> Result<String, Diagnostic> {
+        self.read_quoted(start, '"', "string")
+    }
+
+    fn read_byte_string(&mut self, start: Span) -> Result<Token, Diagnostic> {
+        self.bump(); // skip b
+        let s = self.read_quoted(start, '"', "byte string")?;
+        Ok(Token::ByteStringLiteral(s.chars().map(|c| c as u8).collect()))
+    }
+
+    fn read_char(&mut self, start: Span) -> Result<Token, Diagnostic> {
+        let s = self.read_quoted(start, '\'', "character")?;
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(Token::CharLiteral(c as u8)),
+            (None, _) => Err(Diagnostic::new("empty character literal", self.span_from(start))
+                .with_label("expected one character")),
+            _ => Err(Diagnostic::new("character literal may only contain one character", self.span_from(start))
+                .with_label("use a string for more than one")),
+        }
     }
-}
\ No newline at end of file
+
+    /// Reads a literal between `quote`s, decoding escapes. Only ASCII is taken
+    /// as written and `\xHH` gives the char with that code, so every char of
+    /// the result stands for one byte.
+    fn read_quoted(&mut self, start: Span, quote: char, what: &str) -> Result<String, Diagnostic> {
+        self.bump(); // skip the opening quote
+        let mut s = String::new();
+        // The first bad character or escape, reported once the closing quote is
+        // found so lexing resumes after the literal
+        let mut error = None;
+        loop {
+            let ch = match self.peek_at(0) {
+                Some('\n') if quote == '\'' => None,
+                ch => ch,
+            };
+            let Some(ch) = ch else {
+                return Err(Diagnostic::new(format!("unterminated {} literal", what), start.to(self.here()))
+                    .with_label(format!("{} starts here", what)));
+            };
+            let at = self.here();
+            self.bump();
+            match ch {
+                _ if ch == quote => return error.map_or(Ok(s), Err),
+                '\\' => match self.read_escape(at) {
+                    Ok(ch) => s.push(ch),
+                    Err(d) => { error.get_or_insert(d); }
+                },
+                _ if !ch.is_ascii() => {
+                    error.get_or_insert(Diagnostic::new(format!("non-ASCII character `{}` in a {} literal", ch, what), self.span_from(at))
+                        .with_label("write other bytes as `\\xHH`"));
+                }
+                _ => s.push(ch),
+            }
+        }
+    }
+
+    /// Decodes the escape sequence after the `\` at `at`.
+    fn read_escape(&mut self, at: Span) -> Result<char, Diagnostic> {
+        // At the end of input the caller reports the unterminated literal
+        let Some(ch) = self.peek_at(0) else { return Ok('\\') };
+        self.bump();
+        Ok(match ch {
+            'n' => '\n', 'r' => '\r', 't' => '\t', '0' => '\0',
+            '\\' => '\\', '"' => '"', '\'' => '\'',
+            'x' => {
+                let mut value = 0;
+                for _ in 0..2 {
+                    let Some(digit) = self.peek_at(0).and_then(|c| c.to_digit(16)) else {
+                        return Err(Diagnostic::new("`\\x` needs two hex digits", self.span_from(at))
+                            .with_label("as in `\\x1B`"));
+                    };
+                    value = value * 16 + digit;
+                    self.bump();
+                }
+                char::from(value as u8)
+            }
+            _ => return Err(Diagnostic::new(format!("unknown escape sequence `\\{}`", ch.escape_default()), self.span_from(at))
+                .with_label("expected `\\n`, `\\r`, `\\t`, `\\0`, `\\\\`, `\\\"`, `\\'` or `\\xHH`")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        Lexer::new(source).tokenize().unwrap().into_iter().map(|t| t.token).collect()
+    }
+
+    fn errors(source: &str) -> Vec<Diagnostic> {
+        Lexer::new(source).tokenize().unwrap_err()
+    }
+
+    #[test]
+    fn tokens_carry_spans() {
+        let tokens = Lexer::new("fn main\n  let").tokenize().unwrap();
+        let spans: Vec<_> = tokens.iter().map(|t| (t.span.start, t.span.end, t.span.line, t.span.col)).collect();
+        assert_eq!(spans, [(0, 2, 1, 1), (3, 7, 1, 4), (10, 13, 2, 3), (13, 13, 2, 6)]);
+    }
+
+    #[test]
+    fn keywords_and_operators() {
+        assert_eq!(tokens("let x -> <= >> .. &&"), [
+            Token::Let, Token::Identifier("x".into()), Token::Arrow, Token::LessEqual, Token::Shr, Token::DotDot, Token::AmpAmp, Token::Eof,
+        ]);
+    }
+
+    #[test]
+    fn every_bad_character_is_reported() {
+        let errors = errors("let $ = 1;\n@");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message, "unexpected character `$`");
+        assert_eq!((errors[1].span.line, errors[1].span.col), (2, 1));
+    }
+
+    #[test]
+    fn unterminated_string_stops_at_end_of_input() {
+        let errors = errors("print(\"abc");
+        assert_eq!(errors[0].message, "unterminated string literal");
+        assert_eq!(errors[0].span.end, 10);
+    }
+
+    #[test]
+    fn escapes_decode_to_their_byte() {
+        assert_eq!(tokens(r#""a\n\t\\\"\x1B[2J\0""#)[0], Token::StringLiteral("a\n\t\\\"\x1B[2J\0".into()));
+        assert_eq!(tokens(r#"b"\xFF\x00'""#)[0], Token::ByteStringLiteral(vec![0xFF, 0x00, b'\'']));
+        assert_eq!(tokens(r"'\'' '\xB0' 'A'"), [Token::CharLiteral(b'\''), Token::CharLiteral(0xB0), Token::CharLiteral(b'A'), Token::Eof]);
+    }
+
+    #[test]
+    fn bad_escapes_and_characters_are_reported() {
+        assert_eq!(errors(r#""\x4""#)[0].message, "`\\x` needs two hex digits");
+        assert_eq!(errors(r#""\q""#).len(), 1);
+        assert_eq!(errors("''")[0].message, "empty character literal");
+        assert_eq!(errors("'ab'")[0].message, "character literal may only contain one character");
+        assert_eq!(errors("'a\n'")[0].message, "unterminated character literal");
+    }
+
+    #[test]
+    fn literals_are_limited_to_ascii() {
+        for (source, what) in [("\"caf\u{e9}\"", "string"), ("b\"\u{e9}\"", "byte string"), ("'\u{e9}'", "character")] {
+            let errors = errors(source);
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].message, format!("non-ASCII character `\u{e9}` in a {} literal", what));
+            assert_eq!(source.get(errors[0].span.start..errors[0].span.end), Some("\u{e9}"));
+        }
+    }
+
+    #[test]
+    fn number_literals_in_every_radix() {
+        assert_eq!(tokens("0xB8000 0b1010_0000 0o755 1_000 0XfF"), [
+            Token::Number(0xB8000, None), Token::Number(0b1010_0000, None), Token::Number(0o755, None),
+            Token::Number(1000, None), Token::Number(255, None), Token::Eof,
+        ]);
+        assert_eq!(tokens("0xFFu16 5i8 1_u8"), [
+            Token::Number(255, Some("u16".into())), Token::Number(5, Some("i8".into())), Token::Number(1, Some("u8".into())), Token::Eof,
+        ]);
+    }
+
+    #[test]
+    fn bad_number_literals_are_reported() {
+        assert_eq!(errors("0b102")[0].message, "invalid digit `2` in a binary literal");
+        assert_eq!(errors("0o8")[0].message, "invalid digit `8` in an octal literal");
+        assert_eq!(errors("0x;")[0].message, "missing digits after `0x`");
+        assert_eq!(errors("5u7")[0].message, "invalid suffix `u7` for a number literal");
+        assert_eq!(errors("18446744073709551616")[0].message, "integer literal is too large");
+        assert_eq!(tokens("18446744073709551615")[0], Token::Number(u64::MAX, None));
+    }
+}
+
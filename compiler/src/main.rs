@@ -1,13 +1,20 @@
+mod diagnostics;
 mod lexer;
 mod parser;
+mod sema;
 mod codegen;
+mod codegen_asm;
 
 use std::env;
 use std::fs;
 use std::path::Path;
+use std::process;
+use diagnostics::Diagnostic;
 use lexer::Lexer;
 use parser::Parser;
+use sema::Sema;
 use codegen::Codegen;
+use codegen_asm::AsmGenerator;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -29,35 +36,51 @@ fn main() {
         }
     }
 
-    let code = fs::read_to_string(source_file).expect("Failed to read source file");
-    
-    let mut lexer = Lexer::new(&code);
-    let mut tokens = Vec::new();
-    loop {
-        let token = lexer.next_token();
-        if token == lexer::Token::EOF {
-            break;
+    let code = match fs::read_to_string(source_file) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: failed to read {}: {}", source_file, e);
+            process::exit(1);
         }
-        tokens.push(token);
-    }
-    
+    };
+    let fail = |diags: Vec<Diagnostic>| -> ! {
+        for d in &diags {
+            eprintln!("{}", d.render(source_file, &code));
+        }
+        if diags.len() > 1 {
+            eprintln!("error: aborting due to {} previous errors", diags.len());
+        }
+        process::exit(1);
+    };
+
+    let tokens = Lexer::new(&code).tokenize().unwrap_or_else(|diags| fail(diags));
+
     // println!("Tokens: {:?}", tokens);
 
     let mut parser = Parser::new(tokens);
-    let program = parser.parse_program();
+    let program = parser.parse_program().unwrap_or_else(|diags| fail(diags));
     
     // println!("AST: {:?}", program);
 
+    // Pointers are 16-bit in the real-mode binary, 64-bit in the asm output.
+    let pointer_size = if output_format == "asm" { 8 } else { 2 };
+    let program = Sema::new(pointer_size).check(&program).unwrap_or_else(|diags| fail(diags));
+
     println!("Parsing complete. Generating code...");
     if output_format == "bin" {
         let mut codegen = Codegen::new();
-        let binary = codegen.compile(&program);
+        let binary = codegen.compile(&program).unwrap_or_else(|diags| fail(diags));
         
         let output_file = Path::new(source_file).with_extension("bin");
         fs::write(output_file, binary).expect("Failed to write output file");
         println!("Compilation successful. Output: {}", Path::new(source_file).with_extension("bin").display());
     } else if output_format == "asm" {
-        eprintln!("ASM format not supported in this version yet, generating binary instead.");
+        let mut generator = AsmGenerator::new();
+        let asm = generator.generate(&program);
+
+        let output_file = Path::new(source_file).with_extension("asm");
+        fs::write(&output_file, asm).expect("Failed to write output file");
+        println!("Compilation successful. Output: {}", output_file.display());
     } else {
         eprintln!("Unknown format: {}", output_format);
     }
@@ -1,206 +1,779 @@
-use crate::lexer::Token;
+
+use crate::diagnostics::{Diagnostic, Span};
+use crate::lexer::{SpannedToken, Token};
+use std::fmt;
+
+type PResult<T> = Result<T, Diagnostic>;
 
 #[derive(Debug, Clone, PartialEq)]
-pub enum Type { U8, U16, U32, U64, Void, Pointer(Box<Type>) }
+pub enum Type {
+    U8, U16, U32, U64, I8, I16, I32, I64, Bool, Void,
+    Pointer(Box<Type>),
+    // `far *T`: segment:offset in real mode, an ordinary pointer elsewhere
+    FarPointer(Box<Type>),
+    // A `struct` by name; sema checks it is declared
+    Struct(String),
+    // `[T; N]`
+    Array(Box<Type>, u64),
+}
 
-#[derive(Debug)]
-pub struct Program { pub globals: Vec<Global>, pub functions: Vec<Function> }
+impl Type {
+    /// The integer type named `name`, as in a type or a literal suffix.
+    pub fn integer(name: &str) -> Option<Type> {
+        Some(match name {
+            "u8" => Type::U8, "u16" => Type::U16, "u32" => Type::U32, "u64" => Type::U64,
+            "i8" => Type::I8, "i16" => Type::I16, "i32" => Type::I32, "i64" => Type::I64,
+            _ => return None,
+        })
+    }
+    pub fn is_signed(&self) -> bool { matches!(self, Type::I8 | Type::I16 | Type::I32 | Type::I64) }
+    pub fn is_integer(&self) -> bool { matches!(self, Type::U8 | Type::U16 | Type::U32 | Type::U64) || self.is_signed() }
+    pub fn is_pointer(&self) -> bool { self.pointee().is_some() }
+    pub fn is_far(&self) -> bool { matches!(self, Type::FarPointer(_)) }
+    /// Structs and arrays, which live in memory and are never held in a register.
+    pub fn is_aggregate(&self) -> bool { matches!(self, Type::Struct(_) | Type::Array(..)) }
+    pub fn pointee(&self) -> Option<&Type> {
+        match self { Type::Pointer(pointee) | Type::FarPointer(pointee) => Some(pointee), _ => None }
+    }
+}
 
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Type::U8 => "u8", Type::U16 => "u16", Type::U32 => "u32", Type::U64 => "u64",
+            Type::I8 => "i8", Type::I16 => "i16", Type::I32 => "i32", Type::I64 => "i64",
+            Type::Bool => "bool", Type::Void => "void",
+            Type::Pointer(pointee) => return write!(f, "*{}", pointee),
+            Type::FarPointer(pointee) => return write!(f, "far *{}", pointee),
+            Type::Struct(name) => name,
+            Type::Array(element, len) => return write!(f, "[{}; {}]", element, len),
+        };
+        f.write_str(s)
+    }
+}
+
+/// The parsed program. Sema turns `Program` into `Program<sema::Expr>`, the
+/// same tree with every expression resolved and typed, which the backends consume.
 #[derive(Debug)]
-pub struct Global { pub name: String, pub ty: Type, pub volatile: bool, pub attributes: Vec<Attribute> }
+pub struct Program<E = Expression> { pub structs: Vec<StructDef>, pub globals: Vec<Global<E>>, pub functions: Vec<Function<E>> }
+
+/// `struct Name { field: T, ... }`, optionally `#[packed]`. The parser leaves
+/// `size`, `align` and the field offsets at 0; sema lays the struct out.
+#[derive(Debug, Clone)]
+pub struct StructDef { pub name: String, pub fields: Vec<Field>, pub attributes: Vec<Attribute>, pub size: u64, pub align: u64, pub span: Span }
+
+#[derive(Debug, Clone)]
+pub struct Field { pub name: String, pub ty: Type, pub offset: u64, pub span: Span }
+
+/// A `let` global, or a read-only `const` one.
+#[derive(Debug, Clone)]
+pub struct Global<E = Expression> { pub name: String, pub ty: Type, pub value: Option<E>, pub constant: bool, pub volatile: bool, pub attributes: Vec<Attribute>, pub span: Span }
 
 #[derive(Debug)]
-pub struct Function { pub name: String, pub ret_type: Type, pub body: Vec<Statement>, pub attributes: Vec<Attribute> }
+pub struct Function<E = Expression> { pub name: String, pub params: Vec<Param>, pub ret_type: Type, pub body: Vec<Statement<E>>, pub attributes: Vec<Attribute>, pub span: Span }
+
+impl<E> Function<E> {
+    /// `Some(vector)` for an `#[interrupt]` handler, the vector being optional.
+    pub fn interrupt(&self) -> Option<Option<u64>> {
+        self.attributes.iter().find_map(|attr| match attr { Attribute::Interrupt(vector) => Some(*vector), _ => None })
+    }
+}
 
 #[derive(Debug, Clone)]
-pub enum Attribute { Address(u64), Interrupt }
+pub struct Param { pub name: String, pub ty: Type }
+
+#[derive(Debug, Clone)]
+// `#[interrupt]` optionally names the vector the handler is installed at
+pub enum Attribute { Address(u64), Port(u64), Interrupt(Option<u64>), Packed }
 
 #[derive(Debug)]
-pub enum Statement {
-    Let { name: String, ty: Type, value: Option<Expression>, volatile: bool },
-    Expression(Expression),
-    Loop(Vec<Statement>),
+pub enum Statement<E = Expression> {
+    Let { name: String, ty: Type, value: Option<E>, volatile: bool, span: Span },
+    Expression(E),
+    // Loops carry an optional label for `break label;` / `continue label;`
+    Loop(Option<String>, Vec<Statement<E>>),
+    While(Option<String>, E, Vec<Statement<E>>),
+    For { label: Option<String>, var: String, start: E, end: E, body: Vec<Statement<E>> },
+    Break(Option<String>, Span),
+    Continue(Option<String>, Span),
+    If(E, Vec<Statement<E>>, Option<Vec<Statement<E>>>),
     Asm(String),
-    Assignment(Box<Expression>, Box<Expression>),
-    Clear,
-    Newline,
-    Print(String, u8),
+    Assignment(Box<E>, Box<E>),
+    Return(Option<E>, Span),
+    Clear(Span),
+    Newline(Span),
+    // Text and colour attribute, light grey (0x07) when omitted
+    Print(String, Option<E>, Span),
+    // `printf`, `print_dec` and `print_hex`: the pieces in order, then the colour
+    Printf(Vec<Format<E>>, Option<E>, Span),
 }
 
+/// A piece of formatted output. The parser splits `printf` format strings into
+/// these; `print_dec(x)` is a single `Dec(x)`.
 #[derive(Debug)]
-pub enum Expression {
-    Number(u64), Variable(String),
+pub enum Format<E = Expression> { Text(String), Dec(E), Hex(E) }
+
+#[derive(Debug, Clone)]
+pub struct Expression { pub kind: ExprKind, pub span: Span }
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
+    // A literal and the type given by its suffix, if any
+    Number(u64, Option<Type>), Bool(bool), Variable(String),
     BinaryOp(Box<Expression>, Op, Box<Expression>),
     Dereference(Box<Expression>),
+    // `&place`
+    AddressOf(Box<Expression>),
+    // `value.field`, also through a pointer to a struct
+    Field(Box<Expression>, String),
+    // `array[index]`, also `pointer[index]`
+    Index(Box<Expression>, Box<Expression>),
+    // `[a, b, c]`, only as an initializer
+    Array(Vec<Expression>),
+    // `'c'`, a `u8`
+    Char(u8),
+    // `"text"`, a `*u8` to NUL-terminated read-only data
+    Str(String),
+    // `b"text"`, a read-only `[u8; N]` without a terminator
+    ByteStr(Vec<u8>),
+    Unary(UnaryOp, Box<Expression>),
+    Call(String, Vec<Expression>),
+    // `cast<T>(value)`
+    Cast(Type, Box<Expression>),
+}
+
+#[derive(Debug, Clone)]
+pub enum Op {
+    Add, Sub, Mul, Div, Rem, Shl, Shr,
+    Or, And, Xor, // bitwise | & ^
+    LogicalOr, LogicalAnd,
+    Eq, Ne, Lt, Le, Gt, Ge,
+}
+
+impl Op {
+    pub fn is_comparison(&self) -> bool { matches!(self, Op::Eq | Op::Ne | Op::Lt | Op::Le | Op::Gt | Op::Ge) }
+    pub fn is_logical(&self) -> bool { matches!(self, Op::LogicalOr | Op::LogicalAnd) }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Op::Add => "+", Op::Sub => "-", Op::Mul => "*", Op::Div => "/", Op::Rem => "%", Op::Shl => "<<", Op::Shr => ">>",
+            Op::Or => "|", Op::And => "&", Op::Xor => "^", Op::LogicalOr => "||", Op::LogicalAnd => "&&",
+            Op::Eq => "==", Op::Ne => "!=", Op::Lt => "<", Op::Le => "<=", Op::Gt => ">", Op::Ge => ">=",
+        }
+    }
+
+    /// Binding power of a binary operator token (C precedence, higher binds tighter).
+    fn binary(t: &Token) -> Option<(u8, Op)> {
+        Some(match t {
+            Token::PipePipe => (1, Op::LogicalOr),
+            Token::AmpAmp => (2, Op::LogicalAnd),
+            Token::Pipe => (3, Op::Or),
+            Token::Caret => (4, Op::Xor),
+            Token::Amp => (5, Op::And),
+            Token::EqualEqual => (6, Op::Eq), Token::NotEqual => (6, Op::Ne),
+            Token::LessThan => (7, Op::Lt), Token::LessEqual => (7, Op::Le),
+            Token::GreaterThan => (7, Op::Gt), Token::GreaterEqual => (7, Op::Ge),
+            Token::Shl => (8, Op::Shl), Token::Shr => (8, Op::Shr),
+            Token::Plus => (9, Op::Add), Token::Minus => (9, Op::Sub),
+            Token::Star => (10, Op::Mul), Token::Slash => (10, Op::Div), Token::Percent => (10, Op::Rem),
+            _ => return None,
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
-pub enum Op { Add, Sub, Or, And }
+pub enum UnaryOp { Neg, Not, LogicalNot } // - ~ !
 
-pub struct Parser { tokens: Vec<Token>, pos: usize }
+pub struct Parser { tokens: Vec<SpannedToken>, pos: usize, errors: Vec<Diagnostic>, eof_reported: bool }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self { Parser { tokens, pos: 0 } }
+    pub fn new(tokens: Vec<SpannedToken>) -> Self { Parser { tokens, pos: 0, errors: Vec::new(), eof_reported: false } }
 
-    pub fn parse_program(&mut self) -> Program {
+    /// Parses the whole file. Syntax errors do not stop the parser: each one is
+    /// recorded, the parser resynchronises at the next `;`, `}`, `fn` or statement
+    /// keyword, and all of them are returned together at the end.
+    pub fn parse_program(&mut self) -> Result<Program, Vec<Diagnostic>> {
+        let mut structs = Vec::new();
         let mut globals = Vec::new();
         let mut functions = Vec::new();
         while !self.is_at_end() {
-            let attrs = self.parse_attributes();
-            if self.check(Token::Fn) { functions.push(self.parse_function(attrs)); }
-            else if self.check(Token::Let) || self.check(Token::Volatile) { globals.push(self.parse_global(attrs)); }
-            else { self.advance(); }
+            let attrs = match self.parse_attributes() {
+                Ok(attrs) => attrs,
+                Err(d) => { self.report(d); self.synchronize_item(); continue; }
+            };
+            if self.check(Token::Fn) {
+                match self.parse_function(attrs) {
+                    Ok(f) => functions.push(f),
+                    Err(d) => { self.report(d); self.synchronize_function(); }
+                }
+            } else if self.check(Token::Struct) {
+                match self.parse_struct(attrs) {
+                    Ok(s) => structs.push(s),
+                    Err(d) => { self.report(d); self.synchronize_function(); }
+                }
+            } else if self.check(Token::Let) || self.check(Token::Const) || self.check(Token::Volatile) {
+                let start = self.pos;
+                match self.parse_global(attrs) {
+                    Ok(g) => globals.push(g),
+                    Err(d) => { self.report(d); self.synchronize(start); }
+                }
+            } else {
+                let span = self.span();
+                let found = self.advance();
+                let d = self.unexpected(found, span, "`fn`, `struct` or a global declaration");
+                self.report(d);
+                self.synchronize_item();
+            }
         }
-        Program { globals, functions }
+        if self.errors.is_empty() { Ok(Program { structs, globals, functions }) } else { Err(std::mem::take(&mut self.errors)) }
     }
 
-    fn parse_attributes(&mut self) -> Vec<Attribute> {
+    /// Records a syntax error. Once one has been reported at the end of the file,
+    /// later ones there are knock-on effects of it and are dropped.
+    fn report(&mut self, d: Diagnostic) {
+        if self.is_at_end() {
+            if self.eof_reported { return; }
+            self.eof_reported = true;
+        }
+        self.errors.push(d);
+    }
+
+    /// Skips to the end of the statement that started at token `start`: past the
+    /// next `;`, or up to the `}` closing the enclosing block, the next `fn`, or a
+    /// keyword starting the next statement (`let a = 1 let b = 2;` resumes at the
+    /// second `let`). Balanced `{ }` groups are skipped as a whole.
+    fn synchronize(&mut self, start: usize) {
+        let mut depth = 0usize;
+        loop {
+            match self.peek() {
+                Token::Eof | Token::Fn => return,
+                Token::Let | Token::If | Token::While | Token::Loop | Token::For | Token::Return | Token::Break
+                | Token::Continue | Token::Asm if depth == 0 && self.pos > start => return,
+                Token::SemiColon if depth == 0 => { self.advance(); return; }
+                Token::RBrace if depth == 0 => return,
+                Token::RBrace => {
+                    self.advance();
+                    depth -= 1;
+                    if depth == 0 {
+                        self.match_token(Token::SemiColon);
+                        return;
+                    }
+                }
+                Token::LBrace => { self.advance(); depth += 1; }
+                _ => { self.advance(); }
+            }
+        }
+    }
+
+    /// Skips the rest of a broken function or struct. `let` may still belong to
+    /// a body, so only `fn`, `struct` and attributes start the next item.
+    fn synchronize_function(&mut self) {
+        while !self.is_at_end() && !self.check(Token::Fn) && !self.check(Token::Struct) && !self.check(Token::Hash) { self.advance(); }
+    }
+
+    /// Skips to the start of the next top-level item.
+    fn synchronize_item(&mut self) {
+        while !self.is_at_end() && !self.check(Token::Fn) && !self.check(Token::Struct) && !self.check(Token::Let)
+            && !self.check(Token::Const) && !self.check(Token::Volatile) && !self.check(Token::Hash) {
+            self.advance();
+        }
+    }
+
+    fn parse_attributes(&mut self) -> PResult<Vec<Attribute>> {
         let mut attrs = Vec::new();
         while self.match_token(Token::Hash) {
-            self.expect(Token::LBracket);
+            self.expect(Token::LBracket)?;
+            let span = self.span();
             match self.advance() {
                 Token::Identifier(ref s) if s == "address" => {
-                    self.expect(Token::LParen);
-                    let addr = match self.advance() { Token::Number(n) => n, _ => panic!("Addr expected") };
-                    self.expect(Token::RParen);
+                    self.expect(Token::LParen)?;
+                    let addr = self.expect_number("an address")?;
+                    self.expect(Token::RParen)?;
                     attrs.push(Attribute::Address(addr));
                 }
-                Token::Identifier(ref s) if s == "interrupt" => attrs.push(Attribute::Interrupt),
-                _ => panic!("Unknown attribute"),
+                Token::Identifier(ref s) if s == "port" => {
+                    self.expect(Token::LParen)?;
+                    let port = self.expect_number("a port number")?;
+                    self.expect(Token::RParen)?;
+                    attrs.push(Attribute::Port(port));
+                }
+                Token::Identifier(ref s) if s == "interrupt" => {
+                    let mut vector = None;
+                    if self.match_token(Token::LParen) {
+                        let span = self.span();
+                        match self.advance() {
+                            Token::Identifier(ref s) if s == "vector" => {}
+                            t => return Err(self.unexpected(t, span, "`vector`")),
+                        }
+                        self.expect(Token::Equal)?;
+                        vector = Some(self.expect_number("a vector number")?);
+                        self.expect(Token::RParen)?;
+                    }
+                    attrs.push(Attribute::Interrupt(vector));
+                }
+                Token::Identifier(ref s) if s == "packed" => attrs.push(Attribute::Packed),
+                Token::Identifier(s) => return Err(Diagnostic::new(format!("unknown attribute `{}`", s), span)),
+                t => return Err(self.unexpected(t, span, "an attribute name")),
             }
-            self.expect(Token::RBracket);
+            self.expect(Token::RBracket)?;
         }
-        attrs
+        Ok(attrs)
     }
 
-    fn parse_function(&mut self, attributes: Vec<Attribute>) -> Function {
-        self.expect(Token::Fn);
-        let name = match self.advance() { Token::Identifier(s) => s, _ => panic!("Fn name expected") };
-        self.expect(Token::LParen);
-        while !self.check(Token::RParen) && !self.is_at_end() { 
-            self.advance(); 
-            if self.match_token(Token::Comma) { continue; } 
+    fn parse_function(&mut self, attributes: Vec<Attribute>) -> PResult<Function> {
+        self.expect(Token::Fn)?;
+        let span = self.span();
+        let name = self.expect_identifier("a function name")?;
+        self.expect(Token::LParen)?;
+        let mut params = Vec::new();
+        while !self.check(Token::RParen) && !self.is_at_end() {
+            let name = self.expect_identifier("a parameter name")?;
+            self.expect(Token::Colon)?;
+            params.push(Param { name, ty: self.parse_type()? });
+            if !self.match_token(Token::Comma) { break; }
         }
-        self.expect(Token::RParen);
-        self.expect(Token::Arrow);
-        let ret_type = self.parse_type();
-        self.expect(Token::LBrace);
-        Function { name, ret_type, body: self.parse_block(), attributes }
+        self.expect(Token::RParen)?;
+        self.expect(Token::Arrow)?;
+        let ret_type = self.parse_type()?;
+        self.expect(Token::LBrace)?;
+        Ok(Function { name, params, ret_type, body: self.parse_block()?, attributes, span })
     }
 
-    fn parse_global(&mut self, attributes: Vec<Attribute>) -> Global {
-        let volatile = self.match_token(Token::Volatile);
-        self.expect(Token::Let);
-        let name = match self.advance() { Token::Identifier(s) => s, _ => panic!("Global name") };
-        self.expect(Token::Colon);
-        let ty = self.parse_type();
-        self.expect(Token::SemiColon);
-        Global { name, ty, volatile, attributes }
+    fn parse_struct(&mut self, attributes: Vec<Attribute>) -> PResult<StructDef> {
+        self.expect(Token::Struct)?;
+        let span = self.span();
+        let name = self.expect_identifier("a struct name")?;
+        self.expect(Token::LBrace)?;
+        let mut fields = Vec::new();
+        while !self.check(Token::RBrace) && !self.is_at_end() {
+            let span = self.span();
+            let name = self.expect_identifier("a field name")?;
+            self.expect(Token::Colon)?;
+            fields.push(Field { name, ty: self.parse_type()?, offset: 0, span });
+            if !self.match_token(Token::Comma) { break; }
+        }
+        self.expect(Token::RBrace)?;
+        Ok(StructDef { name, fields, attributes, size: 0, align: 0, span })
     }
 
-    fn parse_block(&mut self) -> Vec<Statement> {
+    fn parse_global(&mut self, attributes: Vec<Attribute>) -> PResult<Global> {
+        let constant = self.match_token(Token::Const);
+        let volatile = !constant && self.match_token(Token::Volatile);
+        if !constant { self.expect(Token::Let)?; }
+        let span = self.span();
+        let name = self.expect_identifier("a global name")?;
+        self.expect(Token::Colon)?;
+        let ty = self.parse_type()?;
+        let value = if self.match_token(Token::Equal) { Some(self.parse_expression()?) } else { None };
+        self.expect(Token::SemiColon)?;
+        Ok(Global { name, ty, value, constant, volatile, attributes, span })
+    }
+
+    fn parse_block(&mut self) -> PResult<Vec<Statement>> {
         let mut stmts = Vec::new();
-        while !self.check(Token::RBrace) && !self.is_at_end() { stmts.push(self.parse_statement()); }
-        self.expect(Token::RBrace);
-        stmts
+        // A `fn` here means the block was never closed; `expect` below reports it.
+        while !self.check(Token::RBrace) && !self.check(Token::Fn) && !self.is_at_end() {
+            let start = self.pos;
+            match self.parse_statement() {
+                Ok(stmt) => stmts.push(stmt),
+                Err(d) => { self.report(d); self.synchronize(start); }
+            }
+        }
+        self.expect(Token::RBrace)?;
+        Ok(stmts)
     }
 
-    fn parse_statement(&mut self) -> Statement {
+    fn parse_statement(&mut self) -> PResult<Statement> {
         if self.match_token(Token::Let) {
-            let name = match self.advance() { Token::Identifier(s) => s, _ => panic!("Var name") };
-            self.expect(Token::Colon);
-            let ty = self.parse_type();
-            self.expect(Token::Equal);
-            let val = self.parse_expression();
-            self.expect(Token::SemiColon);
-            Statement::Let { name, ty, value: Some(val), volatile: false }
-        } else if self.check(Token::Identifier("clear".to_string())) {
-            self.advance(); self.expect(Token::LParen); self.expect(Token::RParen); self.expect(Token::SemiColon);
-            Statement::Clear
-        } else if self.check(Token::Identifier("newline".to_string())) {
-            self.advance(); self.expect(Token::LParen); self.expect(Token::RParen); self.expect(Token::SemiColon);
-            Statement::Newline
+            let span = self.span();
+            let name = self.expect_identifier("a variable name")?;
+            self.expect(Token::Colon)?;
+            let ty = self.parse_type()?;
+            let value = if self.match_token(Token::Equal) { Some(self.parse_expression()?) } else { None };
+            self.expect(Token::SemiColon)?;
+            Ok(Statement::Let { name, ty, value, volatile: false, span })
+        } else if self.check(Token::Identifier("clear".to_string())) || self.check(Token::Identifier("newline".to_string())) {
+            let start = self.span();
+            let clear = self.advance() == Token::Identifier("clear".to_string());
+            self.expect(Token::LParen)?; self.expect(Token::RParen)?;
+            let span = start.to(self.prev_span());
+            self.expect(Token::SemiColon)?;
+            Ok(if clear { Statement::Clear(span) } else { Statement::Newline(span) })
         } else if self.check(Token::Identifier("print".to_string())) {
-            self.advance(); self.expect(Token::LParen);
-            let s = match self.advance() { Token::StringLiteral(s) => s, _ => panic!("Print string expected") };
-            let col = if self.match_token(Token::Comma) {
-                match self.advance() { Token::Number(n) => n as u8, _ => 0x07 }
-            } else { 0x07 };
-            self.expect(Token::RParen); self.expect(Token::SemiColon);
-            Statement::Print(s, col)
-        } else if self.match_token(Token::Loop) {
-            self.expect(Token::LBrace); Statement::Loop(self.parse_block())
+            let start = self.span();
+            self.advance(); self.expect(Token::LParen)?;
+            let s = self.expect_string("a string to print")?;
+            let color = if self.match_token(Token::Comma) { Some(self.parse_expression()?) } else { None };
+            self.expect(Token::RParen)?;
+            let span = start.to(self.prev_span());
+            self.expect(Token::SemiColon)?;
+            Ok(Statement::Print(s, color, span))
+        } else if self.check(Token::Identifier("print_dec".to_string())) || self.check(Token::Identifier("print_hex".to_string())) {
+            let start = self.span();
+            let hex = self.advance() == Token::Identifier("print_hex".to_string());
+            self.expect(Token::LParen)?;
+            let value = self.parse_expression()?;
+            let color = if self.match_token(Token::Comma) { Some(self.parse_expression()?) } else { None };
+            self.expect(Token::RParen)?;
+            let span = start.to(self.prev_span());
+            self.expect(Token::SemiColon)?;
+            Ok(Statement::Printf(vec![if hex { Format::Hex(value) } else { Format::Dec(value) }], color, span))
+        } else if self.check(Token::Identifier("printf".to_string())) {
+            self.parse_printf()
+        } else if matches!(self.peek(), Token::Identifier(_)) && self.peek_next() == Token::Colon {
+            let label = self.expect_identifier("a label")?;
+            self.advance();
+            if !matches!(self.peek(), Token::Loop | Token::While | Token::For) {
+                return Err(self.unexpected(self.peek(), self.span(), "a loop after the label"));
+            }
+            self.parse_loop(Some(label))
+        } else if matches!(self.peek(), Token::Loop | Token::While | Token::For) {
+            self.parse_loop(None)
+        } else if self.check(Token::Break) || self.check(Token::Continue) {
+            let span = self.span();
+            let is_break = self.advance() == Token::Break;
+            let label = if let Token::Identifier(_) = self.peek() { Some(self.expect_identifier("a label")?) } else { None };
+            let span = span.to(self.prev_span());
+            self.expect(Token::SemiColon)?;
+            Ok(if is_break { Statement::Break(label, span) } else { Statement::Continue(label, span) })
+        } else if self.match_token(Token::If) {
+            self.parse_if()
+        } else if self.check(Token::Return) {
+            let span = self.span();
+            self.advance();
+            let value = if self.check(Token::SemiColon) { None } else { Some(self.parse_expression()?) };
+            let span = span.to(self.prev_span());
+            self.expect(Token::SemiColon)?;
+            Ok(Statement::Return(value, span))
         } else if self.match_token(Token::Asm) {
-            self.expect(Token::LParen);
-            let code = match self.advance() { Token::StringLiteral(s) => s, _ => panic!("Asm string") };
-            self.expect(Token::RParen); self.expect(Token::SemiColon);
-            Statement::Asm(code)
+            self.expect(Token::LParen)?;
+            let code = self.expect_string("an assembly string")?;
+            self.expect(Token::RParen)?; self.expect(Token::SemiColon)?;
+            Ok(Statement::Asm(code))
         } else {
-            let expr = self.parse_expression();
+            let expr = self.parse_expression()?;
+            if self.check(Token::Equal) && !matches!(expr.kind, ExprKind::Variable(_) | ExprKind::Dereference(_) | ExprKind::Field(..) | ExprKind::Index(..)) {
+                return Err(Diagnostic::new("invalid left-hand side of assignment", expr.span)
+                    .with_label("cannot assign to this expression"));
+            }
             if self.match_token(Token::Equal) {
-                let rhs = self.parse_expression(); self.expect(Token::SemiColon);
-                Statement::Assignment(Box::new(expr), Box::new(rhs))
-            } else { self.expect(Token::SemiColon); Statement::Expression(expr) }
+                let rhs = self.parse_expression()?; self.expect(Token::SemiColon)?;
+                Ok(Statement::Assignment(Box::new(expr), Box::new(rhs)))
+            } else { self.expect(Token::SemiColon)?; Ok(Statement::Expression(expr)) }
         }
     }
 
-    fn parse_expression(&mut self) -> Expression {
-        self.parse_additive()
+    fn parse_loop(&mut self, label: Option<String>) -> PResult<Statement> {
+        match self.advance() {
+            Token::Loop => {
+                self.expect(Token::LBrace)?;
+                Ok(Statement::Loop(label, self.parse_block()?))
+            }
+            Token::While => {
+                let cond = self.parse_expression()?;
+                self.expect(Token::LBrace)?;
+                Ok(Statement::While(label, cond, self.parse_block()?))
+            }
+            _ => {
+                let var = self.expect_identifier("a loop variable")?;
+                self.expect(Token::In)?;
+                let start = self.parse_expression()?;
+                self.expect(Token::DotDot)?;
+                let end = self.parse_expression()?;
+                self.expect(Token::LBrace)?;
+                Ok(Statement::For { label, var, start, end, body: self.parse_block()? })
+            }
+        }
     }
 
-    fn parse_additive(&mut self) -> Expression {
-        let mut left = self.parse_bitwise();
-        while self.check(Token::Plus) || self.check(Token::Minus) {
-            let op = if self.match_token(Token::Plus) { Op::Add } else { self.advance(); Op::Sub };
-            left = Expression::BinaryOp(Box::new(left), op, Box::new(self.parse_bitwise()));
-        }
-        left
+    /// `if` has been consumed. `else if` chains nest as an `else` block holding one `if`.
+    fn parse_if(&mut self) -> PResult<Statement> {
+        let cond = self.parse_expression()?;
+        self.expect(Token::LBrace)?;
+        let then_block = self.parse_block()?;
+        let else_block = if !self.match_token(Token::Else) { None }
+            else if self.match_token(Token::If) { Some(vec![self.parse_if()?]) }
+            else { self.expect(Token::LBrace)?; Some(self.parse_block()?) };
+        Ok(Statement::If(cond, then_block, else_block))
     }
 
-    fn parse_bitwise(&mut self) -> Expression {
-        let mut left = self.parse_unary();
-        while self.match_token(Token::Pipe) {
-            left = Expression::BinaryOp(Box::new(left), Op::Or, Box::new(self.parse_unary()));
+    fn parse_expression(&mut self) -> PResult<Expression> {
+        self.parse_binary(1)
+    }
+
+    /// Precedence climbing over `Op::binary`: operators binding at least as
+    /// tightly as `min_prec` are folded left-associatively.
+    fn parse_binary(&mut self, min_prec: u8) -> PResult<Expression> {
+        let mut left = self.parse_unary()?;
+        while let Some((prec, op)) = Op::binary(&self.peek()) {
+            if prec < min_prec { break; }
+            self.advance();
+            let right = self.parse_binary(prec + 1)?;
+            // `a < b < c` almost never means what it says.
+            if op.is_comparison() && Op::binary(&self.peek()).is_some_and(|(next, _)| next == prec) {
+                return Err(Diagnostic::new("comparison operators cannot be chained", self.span())
+                    .with_label("use parentheses to compare the result"));
+            }
+            let span = left.span.to(right.span);
+            left = Expression { kind: ExprKind::BinaryOp(Box::new(left), op, Box::new(right)), span };
         }
-        left
+        Ok(left)
     }
 
-    fn parse_unary(&mut self) -> Expression {
-        if self.match_token(Token::Star) { Expression::Dereference(Box::new(self.parse_unary())) }
-        else { self.parse_primary() }
+    fn parse_unary(&mut self) -> PResult<Expression> {
+        let wrap: fn(Box<Expression>) -> ExprKind = match self.peek() {
+            Token::Star => ExprKind::Dereference,
+            Token::Amp => ExprKind::AddressOf,
+            Token::Minus => |e| ExprKind::Unary(UnaryOp::Neg, e),
+            Token::Tilde => |e| ExprKind::Unary(UnaryOp::Not, e),
+            Token::Bang => |e| ExprKind::Unary(UnaryOp::LogicalNot, e),
+            _ => return self.parse_postfix(),
+        };
+        let start = self.span();
+        self.advance();
+        let operand = Box::new(self.parse_unary()?);
+        let span = start.to(operand.span);
+        Ok(Expression { kind: wrap(operand), span })
     }
 
-    fn parse_primary(&mut self) -> Expression {
-        if self.match_token(Token::LParen) {
-            let expr = self.parse_expression();
-            self.expect(Token::RParen);
-            expr
-        } else {
-            match self.advance() {
-                Token::Number(n) => Expression::Number(n),
-                Token::Identifier(s) => Expression::Variable(s),
-                _ => panic!("Expression error at {:?}", self.peek()),
+    /// A primary expression followed by any number of `.field` and `[index]`.
+    fn parse_postfix(&mut self) -> PResult<Expression> {
+        let mut expr = self.parse_primary()?;
+        loop {
+            let start = expr.span;
+            let kind = if self.match_token(Token::Dot) {
+                ExprKind::Field(Box::new(expr), self.expect_identifier("a field name")?)
+            } else if self.match_token(Token::LBracket) {
+                let index = self.parse_expression()?;
+                self.expect(Token::RBracket)?;
+                ExprKind::Index(Box::new(expr), Box::new(index))
+            } else {
+                return Ok(expr);
+            };
+            let span = start.to(self.prev_span());
+            expr = Expression { kind, span };
+        }
+    }
+
+    fn parse_primary(&mut self) -> PResult<Expression> {
+        let span = self.span();
+        let kind = match self.advance() {
+            Token::LParen => {
+                let inner = self.parse_expression()?;
+                self.expect(Token::RParen)?;
+                inner.kind
+            }
+            Token::Number(n, suffix) => ExprKind::Number(n, suffix.and_then(|s| Type::integer(&s))),
+            Token::CharLiteral(c) => ExprKind::Char(c),
+            Token::StringLiteral(s) => ExprKind::Str(s),
+            Token::ByteStringLiteral(bytes) => ExprKind::ByteStr(bytes),
+            Token::LBracket => {
+                let mut elements = Vec::new();
+                while !self.check(Token::RBracket) && !self.is_at_end() {
+                    elements.push(self.parse_expression()?);
+                    if !self.match_token(Token::Comma) { break; }
+                }
+                self.expect(Token::RBracket)?;
+                ExprKind::Array(elements)
+            }
+            Token::True => ExprKind::Bool(true),
+            Token::False => ExprKind::Bool(false),
+            Token::Cast => {
+                self.expect(Token::LessThan)?;
+                let ty = self.parse_type()?;
+                self.expect(Token::GreaterThan)?;
+                self.expect(Token::LParen)?;
+                let value = self.parse_expression()?;
+                self.expect(Token::RParen)?;
+                ExprKind::Cast(ty, Box::new(value))
+            }
+            Token::Identifier(s) if self.match_token(Token::LParen) => {
+                let mut args = Vec::new();
+                while !self.check(Token::RParen) && !self.is_at_end() {
+                    args.push(self.parse_expression()?);
+                    if !self.match_token(Token::Comma) { break; }
+                }
+                self.expect(Token::RParen)?;
+                ExprKind::Call(s, args)
+            }
+            Token::Identifier(s) => ExprKind::Variable(s),
+            t => {
+                // Leave block and statement boundaries for error recovery
+                if matches!(t, Token::LBrace | Token::RBrace | Token::SemiColon) { self.pos -= 1; }
+                return Err(self.unexpected(t, span, "an expression"));
             }
+        };
+        Ok(Expression { kind, span: span.to(self.prev_span()) })
+    }
+
+    fn parse_type(&mut self) -> PResult<Type> {
+        if self.match_token(Token::Star) { return Ok(Type::Pointer(Box::new(self.parse_type()?))); }
+        if self.match_token(Token::LBracket) {
+            let element = self.parse_type()?;
+            self.expect(Token::SemiColon)?;
+            let len = self.expect_number("an array length")?;
+            self.expect(Token::RBracket)?;
+            return Ok(Type::Array(Box::new(element), len));
+        }
+        if self.match_token(Token::Far) {
+            self.expect(Token::Star)?;
+            return Ok(Type::FarPointer(Box::new(self.parse_type()?)));
+        }
+        let span = self.span();
+        match self.advance() {
+            Token::Identifier(s) => match s.as_str() {
+                "bool" => Ok(Type::Bool), "void" => Ok(Type::Void),
+                _ => Ok(Type::integer(&s).unwrap_or(Type::Struct(s))),
+            },
+            t => Err(self.unexpected(t, span, "a type")),
         }
     }
 
-    fn parse_type(&mut self) -> Type {
-        if self.match_token(Token::Star) { Type::Pointer(Box::new(self.parse_type())) }
-        else {
-            match self.advance() {
-                Token::Identifier(s) => match s.as_str() {
-                    "u8" => Type::U8, "u16" => Type::U16, "u32" => Type::U32, "void" => Type::Void,
-                    _ => panic!("Unknown type: {}", s),
-                },
-                _ => panic!("Type expected"),
+    // printf("x={x} y={d}", x, y, color): one argument per placeholder, then an optional colour
+    fn parse_printf(&mut self) -> PResult<Statement> {
+        let start = self.span();
+        self.advance(); self.expect(Token::LParen)?;
+        let format_span = self.span();
+        let format = self.expect_string("a format string")?;
+        let pieces = Self::parse_format(&format, format_span)?;
+        let mut args = Vec::new();
+        while self.match_token(Token::Comma) { args.push(self.parse_expression()?); }
+        self.expect(Token::RParen)?;
+        let span = start.to(self.prev_span());
+        self.expect(Token::SemiColon)?;
+
+        let holes = pieces.iter().filter(|p| !matches!(p, Format::Text(_))).count();
+        if args.len() != holes && args.len() != holes + 1 {
+            return Err(Diagnostic::new(format!("format string has {} placeholder(s) but {} argument(s) were supplied", holes, args.len()), span)
+                .with_label("expected one argument per placeholder, then an optional colour"));
+        }
+        let color = if args.len() > holes { args.pop() } else { None };
+        let mut args = args.into_iter();
+        let pieces = pieces.into_iter().map(|piece| match piece {
+            Format::Text(text) => Format::Text(text),
+            Format::Dec(()) => Format::Dec(args.next().unwrap()),
+            Format::Hex(()) => Format::Hex(args.next().unwrap()),
+        }).collect();
+        Ok(Statement::Printf(pieces, color, span))
+    }
+
+    // Splits a format string at `{}`/`{d}` (decimal) and `{x}` (hex); `{{` and `}}` are literal braces
+    fn parse_format(format: &str, span: Span) -> PResult<Vec<Format<()>>> {
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => { chars.next(); text.push('{'); }
+                '}' if chars.as_str().starts_with('}') => { chars.next(); text.push('}'); }
+                '{' => {
+                    let Some(end) = chars.as_str().find('}') else {
+                        return Err(Diagnostic::new("unclosed `{` in format string", span).with_label("write `{{` for a literal brace"));
+                    };
+                    let spec = &chars.as_str()[..end];
+                    let piece = match spec {
+                        "" | "d" => Format::Dec(()),
+                        "x" => Format::Hex(()),
+                        _ => return Err(Diagnostic::new(format!("unknown format specifier `{{{}}}`", spec), span)
+                            .with_label("expected `{}`, `{d}` or `{x}`")),
+                    };
+                    chars = chars.as_str()[end + 1..].chars();
+                    if !text.is_empty() { pieces.push(Format::Text(std::mem::take(&mut text))); }
+                    pieces.push(piece);
+                }
+                '}' => return Err(Diagnostic::new("unmatched `}` in format string", span).with_label("write `}}` for a literal brace")),
+                c => text.push(c),
             }
         }
+        if !text.is_empty() { pieces.push(Format::Text(text)); }
+        Ok(pieces)
+    }
+
+    fn unexpected(&self, found: Token, span: Span, what: &str) -> Diagnostic {
+        Diagnostic::new(format!("expected {}, found {}", what, found), span).with_label(format!("expected {}", what))
+    }
+
+    fn expect_identifier(&mut self, what: &str) -> PResult<String> {
+        let span = self.span();
+        match self.advance() { Token::Identifier(s) => Ok(s), t => Err(self.unexpected(t, span, what)) }
     }
 
-    fn peek(&self) -> Token { self.tokens.get(self.pos).cloned().unwrap_or(Token::EOF) }
+    fn expect_string(&mut self, what: &str) -> PResult<String> {
+        let span = self.span();
+        match self.advance() { Token::StringLiteral(s) => Ok(s), t => Err(self.unexpected(t, span, what)) }
+    }
+
+    fn expect_number(&mut self, what: &str) -> PResult<u64> {
+        let span = self.span();
+        match self.advance() { Token::Number(n, _) => Ok(n), t => Err(self.unexpected(t, span, what)) }
+    }
+
+    fn peek(&self) -> Token { self.tokens.get(self.pos).map(|t| t.token.clone()).unwrap_or(Token::Eof) }
+    fn span(&self) -> Span { self.tokens.get(self.pos).or(self.tokens.last()).map(|t| t.span).unwrap_or_default() }
+    fn prev_span(&self) -> Span { self.tokens.get(self.pos.wrapping_sub(1)).map(|t| t.span).unwrap_or_default() }
+    fn peek_next(&self) -> Token { self.tokens.get(self.pos + 1).map(|t| t.token.clone()).unwrap_or(Token::Eof) }
     fn check(&self, t: Token) -> bool { self.peek() == t }
-    fn is_at_end(&self) -> bool { self.peek() == Token::EOF }
+    fn is_at_end(&self) -> bool { self.peek() == Token::Eof }
     fn advance(&mut self) -> Token { let t = self.peek(); if !self.is_at_end() { self.pos += 1; } t }
     fn match_token(&mut self, t: Token) -> bool { if self.check(t) { self.advance(); true } else { false } }
-    fn expect(&mut self, t: Token) { if !self.match_token(t.clone()) { panic!("Expected {:?} got {:?}", t, self.peek()); } }
-}
\ No newline at end of file
+    fn expect(&mut self, t: Token) -> PResult<()> {
+        if self.match_token(t.clone()) { return Ok(()); }
+        // A missing `;` is reported right after the previous token, like rustc does.
+        let span = if t == Token::SemiColon && self.pos > 0 {
+            let s = self.prev_span();
+            Span { start: s.end, end: s.end, line: s.line, col: s.col + (s.end - s.start) }
+        } else { self.span() };
+        Err(self.unexpected(self.peek(), span, &t.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn parse(source: &str) -> Result<Program, Vec<Diagnostic>> {
+        Parser::new(Lexer::new(source).tokenize().unwrap()).parse_program()
+    }
+
+    fn errors(source: &str) -> Vec<(String, usize)> {
+        parse(source).unwrap_err().into_iter().map(|d| (d.message, d.span.line)).collect()
+    }
+
+    #[test]
+    fn reports_every_broken_statement() {
+        let source = "fn kernel_main() -> void {\n let a: u16 = 1\n let b: u16 = ;\n let c: u16 = 2 3;\n b = ;\n}\n";
+        assert_eq!(errors(source), [
+            ("expected `;`, found `let`".to_string(), 2),
+            ("expected an expression, found `;`".to_string(), 3),
+            ("expected `;`, found number `3`".to_string(), 4),
+            ("expected an expression, found `;`".to_string(), 5),
+        ]);
+    }
+
+    #[test]
+    fn recovery_skips_balanced_blocks() {
+        let source = "fn kernel_main() -> void {\n let a: u16 = { 1; 2 };\n let b: u16 = 3;\n}\nfn f() -> void { let ; }\n";
+        let errors = errors(source);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[1], ("expected a variable name, found `;`".to_string(), 5));
+    }
+
+    #[test]
+    fn end_of_file_is_reported_once() {
+        assert_eq!(errors("fn kernel_main() -> void {\n let a: u16 = 1"), [("expected `;`, found end of file".to_string(), 2)]);
+    }
+
+    #[test]
+    fn stray_top_level_tokens_are_errors() {
+        let errors = errors("42\nfn kernel_main() -> void {}\n");
+        assert_eq!(errors, [("expected `fn`, `struct` or a global declaration, found number `42`".to_string(), 1)]);
+    }
+
+    #[test]
+    fn parses_a_function_with_parameters() {
+        let program = parse("fn putc(c: u8, attr: u8) -> void {}").unwrap();
+        let params: Vec<_> = program.functions[0].params.iter().map(|p| (p.name.as_str(), p.ty.clone())).collect();
+        assert_eq!(params, [("c", Type::U8), ("attr", Type::U8)]);
+    }
+}
@@ -1,2 +1,1464 @@
-// Semantic Analysis Module
-// TODO: Implement strict type checking and volatile analysis
+// Semantic analysis: name resolution and type checking
+//
+// Runs between the parser and the backends. Every identifier is resolved to a
+// local (parameters included) or a global, every expression gets a type, and
+// the result is the parsed tree with `Expr` nodes in place of `Expression`.
+// The backends trust it: they never see an unresolved name or an ill-typed
+// operation.
+//
+// Typing rules:
+//   * Integer literals take their type from context: the declared type of a
+//     `let`, the parameter they are passed to, the other operand of a binary
+//     operator, or from a suffix (`0xFFu16`). Without either they are the
+//     smallest of `u16`, `u32` and `u64` that holds them (of `i16`, `i32` and
+//     `i64` when negative; only `u16` and `i16` in real mode). Arithmetic on
+//     literals alone is folded exactly into one literal typed the same way, so
+//     `1193182 / 65536` is a `u16` 18. A literal must fit in its type, except as
+//     the operand of a `cast`, which truncates it.
+//     Character literals are always `u8`.
+//   * Both operands of a binary operator have the same type after implicit
+//     widening. Widening is the only implicit conversion: to a larger integer
+//     of the same signedness, or from unsigned to a larger signed integer.
+//     Narrowing, signedness changes, integer <-> pointer and bool <-> integer
+//     need `cast<T>(value)`. Implicit widenings become explicit casts in the
+//     typed tree.
+//   * Integer literals also convert to pointers, for fixed addresses such as
+//     `let vga: far *u16 = 0xB8000;`. In real mode a near pointer only reaches
+//     the first 64 KiB (DS is 0) and a far pointer the first 1 MiB; literals
+//     and `#[address(N)]` globals beyond that are errors.
+//   * A near pointer converts implicitly to a far pointer to the same type.
+//     In real mode a far pointer cannot be cast back to a near one, which
+//     would drop its segment.
+//   * `&x` is a `*T` to a local or global (a `far *T` for a real-mode global
+//     above 64 KiB); `&*p` is `p`.
+//   * Global initializers are constant expressions, folded to a single literal
+//     of the global's type (an array literal of them for an array); `#[address]`
+//     and `#[port]` globals have none. `const` globals must have one and cannot
+//     be assigned to, neither whole nor through their elements or fields.
+//   * `#[interrupt]` functions take no parameters, return `void` and are only
+//     entered by the CPU, never called; each vector has at most one handler.
+//   * Reading a `#[port(N)]` global is an `inb`/`inw`/`inl` of its width and
+//     assigning to it an `outb`/`outw`/`outl`; such globals have no address.
+//   * Structs are laid out here: naturally, each field aligned to its size (a
+//     nested struct to its largest field) and the struct padded to its largest
+//     alignment, or with no padding at all when `#[packed]`. A struct is only
+//     used through its fields or its address: it cannot be assigned, passed or
+//     returned whole. `p.field` on a pointer to a struct is `(*p).field`, and
+//     `&s.field` is the struct's address plus the field's offset.
+//   * `[T; N]` arrays are used like structs: through their elements or their
+//     address, or whole only when a `let` or a global is initialized with an
+//     array literal of exactly N elements. `a[i]` scales `i` by the element
+//     size; a constant index is checked against N. `p[i]` on a pointer is
+//     `*(p + i)`.
+//   * `"text"` is a `*u8` to NUL-terminated read-only data, also allowed in
+//     global initializers. `b"text"` is a read-only `[u8; N]` place, N being
+//     its length; it also initializes a `[u8; N]` like an array literal.
+//   * `ptr + n` and `ptr - n` move by `n` pointees, like C; `*void` moves by bytes.
+//   * Comparisons, `&&`, `||` and `!` produce `bool`. Conditions may be `bool`,
+//     integers or pointers, anything nonzero being true.
+
+use crate::diagnostics::{Diagnostic, Span};
+use crate::parser::{Program, StructDef, Field, Global, Function, Statement, Format, Expression, ExprKind as Ast, Op, UnaryOp, Type, Attribute};
+use std::collections::{HashMap, HashSet};
+
+type SResult<T> = Result<T, Diagnostic>;
+
+/// A resolved expression and its type.
+#[derive(Debug, Clone)]
+pub struct Expr { pub kind: ExprKind, pub ty: Type, pub span: Span }
+
+impl Expr {
+    /// The value of an integer literal.
+    pub fn constant(&self) -> Option<u64> {
+        match self.kind { ExprKind::Number(n) => Some(n), _ => None }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
+    Number(u64),
+    /// A `let` or a parameter of the current function.
+    Local(String),
+    Global(String),
+    Binary(Box<Expr>, Op, Box<Expr>),
+    Dereference(Box<Expr>),
+    /// Address of a `Local` or `Global`.
+    AddressOf(Box<Expr>),
+    /// An element of an array-typed place: the second operand is its offset in
+    /// bytes, a `Number` when it is constant.
+    Index(Box<Expr>, Box<Expr>),
+    /// An array literal, only found as the initializer of a `let` or a global.
+    Array(Vec<Expr>),
+    /// The address of a NUL-terminated string in read-only data, a `*u8`.
+    Str(String),
+    /// A byte string: a read-only `[u8; N]` place.
+    ByteStr(Vec<u8>),
+    /// A field of a struct-typed place (a local, global, dereference or field),
+    /// this many bytes into it.
+    Field(Box<Expr>, u64),
+    Unary(UnaryOp, Box<Expr>),
+    Call(String, Vec<Expr>),
+    Builtin(Builtin, Vec<Expr>),
+    /// Converts the operand to the type of this node.
+    Cast(Box<Expr>),
+}
+
+/// Functions provided by the compiler and expanded inline by the backends.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Builtin {
+    /// `read_key() -> u16`: waits for a key, scancode in the high byte and ASCII in the low byte.
+    ReadKey,
+    /// `key_available() -> bool`: whether `read_key` would return without waiting.
+    KeyAvailable,
+    /// `set_video_mode(mode: u8)`: 3 is 80x25 text, 0x13 is 320x200 with 256 colours.
+    SetVideoMode,
+    /// `set_cursor(row: u8, col: u8)`
+    SetCursor,
+    /// `get_cursor() -> u16`: row in the high byte, column in the low byte.
+    GetCursor,
+    /// `hide_cursor()`: until the next `set_video_mode`.
+    HideCursor,
+    /// `put_pixel(x: u16, y: u16, colour: u8)`: writes to the mode 13h framebuffer.
+    PutPixel,
+    /// `inb(port: u16) -> u8`, `inw` and `inl` read a byte, word or dword from an I/O port.
+    Inb, Inw, Inl,
+    /// `outb(port: u16, value: u8)`, `outw` and `outl` write one.
+    Outb, Outw, Outl,
+}
+
+impl Builtin {
+    pub fn from_name(name: &str) -> Option<Builtin> {
+        match name {
+            "read_key" => Some(Builtin::ReadKey),
+            "key_available" => Some(Builtin::KeyAvailable),
+            "set_video_mode" => Some(Builtin::SetVideoMode),
+            "set_cursor" => Some(Builtin::SetCursor),
+            "get_cursor" => Some(Builtin::GetCursor),
+            "hide_cursor" => Some(Builtin::HideCursor),
+            "put_pixel" => Some(Builtin::PutPixel),
+            "inb" => Some(Builtin::Inb),
+            "inw" => Some(Builtin::Inw),
+            "inl" => Some(Builtin::Inl),
+            "outb" => Some(Builtin::Outb),
+            "outw" => Some(Builtin::Outw),
+            "outl" => Some(Builtin::Outl),
+            _ => None,
+        }
+    }
+
+    /// Parameter and return types.
+    fn signature(self) -> (Vec<Type>, Type) {
+        match self {
+            Builtin::ReadKey => (vec![], Type::U16),
+            Builtin::KeyAvailable => (vec![], Type::Bool),
+            Builtin::SetVideoMode => (vec![Type::U8], Type::Void),
+            Builtin::SetCursor => (vec![Type::U8, Type::U8], Type::Void),
+            Builtin::GetCursor => (vec![], Type::U16),
+            Builtin::HideCursor => (vec![], Type::Void),
+            Builtin::PutPixel => (vec![Type::U16, Type::U16, Type::U8], Type::Void),
+            Builtin::Inb => (vec![Type::U16], Type::U8),
+            Builtin::Inw => (vec![Type::U16], Type::U16),
+            Builtin::Inl => (vec![Type::U16], Type::U32),
+            Builtin::Outb => (vec![Type::U16, Type::U8], Type::Void),
+            Builtin::Outw => (vec![Type::U16, Type::U16], Type::Void),
+            Builtin::Outl => (vec![Type::U16, Type::U32], Type::Void),
+        }
+    }
+}
+
+pub struct Sema {
+    /// Size of a pointer on the target: 2 in real mode, 8 in long mode.
+    pointer_size: u64,
+    /// Every struct by name, laid out before anything else is checked.
+    structs: HashMap<String, StructDef>,
+    globals: HashMap<String, Type>,
+    /// Names of the `const` globals.
+    constants: HashSet<String>,
+    /// `#[address(N)]` and `#[port(N)]` of the globals that have one.
+    addresses: HashMap<String, u64>,
+    ports: HashMap<String, u64>,
+    /// Parameter and return types of every function.
+    signatures: HashMap<String, (Vec<Type>, Type)>,
+    /// Names of the `#[interrupt]` functions.
+    handlers: HashSet<String>,
+    /// Block scopes of the current function, innermost last; the first holds the parameters.
+    scopes: Vec<HashMap<String, Type>>,
+    ret_type: Type,
+    /// Labels of the enclosing loops, innermost last.
+    loops: Vec<Option<String>>,
+    errors: Vec<Diagnostic>,
+}
+
+impl Sema {
+    pub fn new(pointer_size: u64) -> Self {
+        Sema {
+            pointer_size, structs: HashMap::new(), globals: HashMap::new(), constants: HashSet::new(), addresses: HashMap::new(), ports: HashMap::new(), signatures: HashMap::new(), handlers: HashSet::new(),
+            scopes: Vec::new(), ret_type: Type::Void, loops: Vec::new(), errors: Vec::new(),
+        }
+    }
+
+    /// Checks the whole program, reporting every error found rather than stopping at the first.
+    pub fn check(&mut self, program: &Program) -> Result<Program<Expr>, Vec<Diagnostic>> {
+        for def in &program.structs {
+            for attr in &def.attributes {
+                if !matches!(attr, Attribute::Packed) {
+                    self.errors.push(Diagnostic::new("only `#[packed]` applies to structs", def.span)
+                        .with_label("this is a struct"));
+                }
+            }
+            if self.structs.insert(def.name.clone(), def.clone()).is_some() {
+                self.errors.push(Self::redefined(&def.name, def.span));
+            }
+        }
+        let mut laid_out = HashSet::new();
+        for def in &program.structs {
+            self.layout_struct(&def.name, &mut laid_out, &mut Vec::new());
+        }
+        for global in &program.globals {
+            if global.ty == Type::Void {
+                self.errors.push(Diagnostic::new(format!("global `{}` cannot have type `void`", global.name), global.span));
+            }
+            if let Err(d) = self.check_type(&global.ty, global.span) { self.errors.push(d); }
+            if global.constant {
+                if global.value.is_none() {
+                    self.errors.push(Diagnostic::new(format!("constant `{}` needs a value", global.name), global.span)
+                        .with_label("add `= value`"));
+                }
+                self.constants.insert(global.name.clone());
+            }
+            for attr in &global.attributes {
+                match attr {
+                    Attribute::Address(addr) => {
+                        if self.real_mode() && *addr > 0xFFFFF {
+                            self.errors.push(Diagnostic::new(format!("address {:#X} is beyond the 1 MiB real-mode address space", addr), global.span)
+                                .with_label("not reachable from a 16-bit kernel"));
+                        }
+                        self.addresses.insert(global.name.clone(), *addr);
+                    }
+                    Attribute::Port(port) => {
+                        if *port > 0xFFFF {
+                            self.errors.push(Diagnostic::new(format!("port {:#X} is out of range", port), global.span)
+                                .with_label("I/O ports are numbered 0 to 0xFFFF"));
+                        }
+                        if !matches!(global.ty, Type::U8 | Type::U16 | Type::U32) {
+                            self.errors.push(Diagnostic::new(format!("port global `{}` cannot have type `{}`", global.name, global.ty), global.span)
+                                .with_label("expected `u8`, `u16` or `u32`, the width of the port"));
+                        }
+                        self.ports.insert(global.name.clone(), *port);
+                    }
+                    Attribute::Interrupt(_) => {
+                        self.errors.push(Diagnostic::new("`#[interrupt]` only applies to functions", global.span)
+                            .with_label("this is a global"));
+                    }
+                    Attribute::Packed => {
+                        self.errors.push(Diagnostic::new("`#[packed]` only applies to structs", global.span)
+                            .with_label("this is a global"));
+                    }
+                }
+            }
+            if self.addresses.contains_key(&global.name) && self.ports.contains_key(&global.name) {
+                self.errors.push(Diagnostic::new(format!("global `{}` cannot be both `#[address]` and `#[port]`", global.name), global.span));
+            }
+            if self.globals.insert(global.name.clone(), global.ty.clone()).is_some() {
+                self.errors.push(Self::redefined(&global.name, global.span));
+            }
+        }
+        // Signatures first, so calls to functions defined further down resolve.
+        let mut vectors = HashSet::new();
+        for func in &program.functions {
+            if Builtin::from_name(&func.name).is_some() {
+                self.errors.push(Diagnostic::new(format!("the name `{}` is reserved for a builtin", func.name), func.span)
+                    .with_label("rename this function"));
+            }
+            self.check_function_attributes(func, &mut vectors);
+            for ty in func.params.iter().map(|p| &p.ty) {
+                if let Err(d) = self.check_type(ty, func.span) { self.errors.push(d); }
+                if ty.is_aggregate() {
+                    self.errors.push(Diagnostic::new(format!("`{}` cannot be passed by value", ty), func.span)
+                        .with_label(format!("take a `*{}` instead", ty)));
+                }
+            }
+            if let Err(d) = self.check_type(&func.ret_type, func.span) { self.errors.push(d); }
+            if func.ret_type.is_aggregate() {
+                self.errors.push(Diagnostic::new(format!("`{}` cannot be returned by value", func.ret_type), func.span)
+                    .with_label(format!("return a `*{}` instead", func.ret_type)));
+            }
+            let params = func.params.iter().map(|p| p.ty.clone()).collect();
+            if self.signatures.insert(func.name.clone(), (params, func.ret_type.clone())).is_some() {
+                self.errors.push(Self::redefined(&func.name, func.span));
+            }
+        }
+        let structs = program.structs.iter().map(|def| self.structs[&def.name].clone()).collect();
+        let globals = program.globals.iter().map(|global| self.check_global(global)).collect();
+        let functions = program.functions.iter().map(|func| self.check_function(func)).collect();
+        if self.errors.is_empty() {
+            Ok(Program { structs, globals, functions })
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    /// Checks the attributes of `func`; `vectors` collects the interrupt vectors taken so far.
+    fn check_function_attributes(&mut self, func: &Function, vectors: &mut HashSet<u64>) {
+        for attr in &func.attributes {
+            let vector = match attr {
+                Attribute::Address(_) | Attribute::Port(_) => {
+                    self.errors.push(Diagnostic::new("`#[address]` and `#[port]` only apply to globals", func.span)
+                        .with_label("this is a function"));
+                    continue;
+                }
+                Attribute::Packed => {
+                    self.errors.push(Diagnostic::new("`#[packed]` only applies to structs", func.span)
+                        .with_label("this is a function"));
+                    continue;
+                }
+                Attribute::Interrupt(vector) => vector,
+            };
+            self.handlers.insert(func.name.clone());
+            if !func.params.is_empty() {
+                self.errors.push(Diagnostic::new(format!("interrupt handler `{}` cannot take parameters", func.name), func.span)
+                    .with_label("the CPU passes none"));
+            }
+            if func.ret_type != Type::Void {
+                self.errors.push(Diagnostic::new(format!("interrupt handler `{}` must return `void`", func.name), func.span)
+                    .with_label(format!("returns `{}`", func.ret_type)));
+            }
+            match vector {
+                Some(vector) if *vector > 0xFF => {
+                    self.errors.push(Diagnostic::new(format!("interrupt vector {:#X} is out of range", vector), func.span)
+                        .with_label("vectors are numbered 0 to 0xFF"));
+                }
+                Some(vector) if !vectors.insert(*vector) => {
+                    self.errors.push(Diagnostic::new(format!("interrupt vector {:#X} already has a handler", vector), func.span)
+                        .with_label("second handler for this vector"));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Lays out struct `name` in `self.structs`, after the structs it holds by
+    /// value. `active` are the structs being laid out further up, which it
+    /// cannot hold by value.
+    fn layout_struct(&mut self, name: &str, laid_out: &mut HashSet<String>, active: &mut Vec<String>) {
+        if !laid_out.insert(name.to_string()) { return; }
+        let def = self.structs[name].clone();
+        let packed = def.attributes.iter().any(|attr| matches!(attr, Attribute::Packed));
+        active.push(name.to_string());
+        let mut fields: Vec<Field> = Vec::new();
+        let (mut offset, mut align): (u64, u64) = (0, 1);
+        for field in &def.fields {
+            let mut held = &field.ty;
+            while let Type::Array(element, _) = held { held = element; }
+            if let Type::Struct(inner) = held {
+                if active.contains(inner) {
+                    self.errors.push(Diagnostic::new(format!("recursive struct `{}` has infinite size", name), def.span)
+                        .with_label(format!("field `{}` holds `{}` by value; use a pointer", field.name, inner)));
+                    continue;
+                }
+                if self.structs.contains_key(inner) { self.layout_struct(inner, laid_out, active); }
+            }
+            if let Err(d) = self.check_type(&field.ty, field.span) { self.errors.push(d); continue; }
+            if field.ty == Type::Void {
+                self.errors.push(Diagnostic::new(format!("field `{}` cannot have type `void`", field.name), field.span));
+                continue;
+            }
+            if fields.iter().any(|f| f.name == field.name) {
+                self.errors.push(Diagnostic::new(format!("field `{}` is declared more than once", field.name), field.span)
+                    .with_label("redeclared here"));
+                continue;
+            }
+            let field_align = if packed { 1 } else { self.align_of(&field.ty) };
+            offset = offset.next_multiple_of(field_align);
+            fields.push(Field { offset, ..field.clone() });
+            offset += self.size_of(&field.ty);
+            align = align.max(field_align);
+        }
+        active.pop();
+        let size = offset.next_multiple_of(align);
+        if self.real_mode() && size > 0xFFFF {
+            self.errors.push(Diagnostic::new(format!("struct `{}` is larger than 64 KiB", name), def.span)
+                .with_label("it does not fit in a real-mode segment"));
+        }
+        self.structs.insert(name.to_string(), StructDef { fields, size, align, ..def });
+    }
+
+    /// Checks every struct named in `ty` is declared, and that arrays hold
+    /// something and fit in a real-mode segment.
+    fn check_type(&self, ty: &Type, span: Span) -> SResult<()> {
+        match ty {
+            Type::Pointer(pointee) | Type::FarPointer(pointee) => self.check_type(pointee, span),
+            Type::Array(element, _) => {
+                if **element == Type::Void {
+                    return Err(Diagnostic::new("arrays cannot hold `void`", span).with_label(format!("in `{}`", ty)));
+                }
+                self.check_type(element, span)?;
+                if self.real_mode() && self.size_of(ty) > 0xFFFF {
+                    return Err(Diagnostic::new(format!("`{}` is larger than 64 KiB", ty), span)
+                        .with_label("it does not fit in a real-mode segment"));
+                }
+                Ok(())
+            }
+            Type::Struct(name) if !self.structs.contains_key(name) => {
+                Err(Diagnostic::new(format!("unknown type `{}`", name), span).with_label("no struct with this name"))
+            }
+            _ if self.real_mode() && Self::is_wide(ty) => Err(Self::too_wide(ty, span)),
+            _ => Ok(()),
+        }
+    }
+
+    /// 32 and 64-bit integers, which the real-mode backend cannot hold: its
+    /// values live in AX.
+    fn is_wide(ty: &Type) -> bool {
+        matches!(ty, Type::U32 | Type::I32 | Type::U64 | Type::I64)
+    }
+
+    fn too_wide(ty: &Type, span: Span) -> Diagnostic {
+        Diagnostic::new(format!("`{}` is not supported by the 16-bit target", ty), span)
+            .with_label("real-mode values are at most 16 bits wide")
+    }
+
+    fn aggregate_value(ty: &Type, span: Span) -> Diagnostic {
+        let parts = if matches!(ty, Type::Array(..)) { "elements" } else { "fields" };
+        Diagnostic::new(format!("`{}` cannot be used as a value", ty), span)
+            .with_label(format!("read or assign its {}, or take its address", parts))
+    }
+
+    fn redefined(name: &str, span: Span) -> Diagnostic {
+        Diagnostic::new(format!("the name `{}` is defined multiple times", name), span).with_label("redefined here")
+    }
+
+    /// Checks the initializer of `global` and folds it to a constant.
+    fn check_global(&mut self, global: &Global) -> Global<Expr> {
+        let value = global.value.as_ref().filter(|_| global.ty != Type::Void).and_then(|value| {
+            let fixed = global.attributes.iter().any(|attr| matches!(attr, Attribute::Address(_) | Attribute::Port(_)));
+            let folded = if fixed {
+                Err(Diagnostic::new(format!("global `{}` cannot have an initializer", global.name), value.span)
+                    .with_label("`#[address]` and `#[port]` globals have no storage in the image"))
+            } else {
+                self.check_initializer(value, &global.ty).and_then(|value| self.fold_initializer(value))
+            };
+            folded.map_err(|d| self.errors.push(d)).ok()
+        });
+        Global {
+            name: global.name.clone(), ty: global.ty.clone(), value, constant: global.constant, volatile: global.volatile,
+            attributes: global.attributes.clone(), span: global.span,
+        }
+    }
+
+    /// Checks the initializer of a `let` or a global: an array literal for an
+    /// array, element by element, a byte string for a `[u8; N]`, or any value
+    /// converting to `ty`.
+    fn check_initializer(&mut self, value: &Expression, ty: &Type) -> SResult<Expr> {
+        if let (Ast::ByteStr(bytes), Type::Array(element, len)) = (&value.kind, ty) {
+            if **element == Type::U8 {
+                if bytes.len() as u64 != *len {
+                    return Err(Diagnostic::new(format!("expected {} byte(s) for `{}`, found {}", len, ty, bytes.len()), value.span)
+                        .with_label(format!("this byte string has {} byte(s)", bytes.len())));
+                }
+                let byte = |&b: &u8| Expr { kind: ExprKind::Number(b as u64), ty: Type::U8, span: value.span };
+                return Ok(Expr { kind: ExprKind::Array(bytes.iter().map(byte).collect()), ty: ty.clone(), span: value.span });
+            }
+        }
+        let Ast::Array(elements) = &value.kind else { return self.check_as(value, ty) };
+        let Type::Array(element, len) = ty else {
+            return Err(Diagnostic::new(format!("mismatched types: expected `{}`, found an array literal", ty), value.span)
+                .with_label(format!("expected `{}`", ty)));
+        };
+        if elements.len() as u64 != *len {
+            return Err(Diagnostic::new(format!("expected {} element(s) for `{}`, found {}", len, ty, elements.len()), value.span)
+                .with_label(format!("this literal has {} element(s)", elements.len())));
+        }
+        let elements = elements.iter().map(|e| self.check_initializer(e, element)).collect::<SResult<_>>()?;
+        Ok(Expr { kind: ExprKind::Array(elements), ty: ty.clone(), span: value.span })
+    }
+
+    /// Folds a global initializer to literals, element by element for an array.
+    /// String addresses are left for the backends to place.
+    fn fold_initializer(&self, value: Expr) -> SResult<Expr> {
+        match value.kind {
+            ExprKind::Str(_) => Ok(value),
+            ExprKind::Array(elements) => {
+                let elements = elements.into_iter().map(|e| self.fold_initializer(e)).collect::<SResult<_>>()?;
+                Ok(Expr { kind: ExprKind::Array(elements), ..value })
+            }
+            _ => Ok(Expr { kind: ExprKind::Number(self.fold(&value)?), ..value }),
+        }
+    }
+
+    /// Evaluates a constant expression, wrapped to the width of its type (signed
+    /// values sign-extended to 64 bits).
+    fn fold(&self, expr: &Expr) -> SResult<u64> {
+        let value = match &expr.kind {
+            ExprKind::Number(n) => *n,
+            ExprKind::Cast(value) => self.fold(value)?,
+            ExprKind::Unary(op, operand) => {
+                let v = self.fold(operand)?;
+                match op {
+                    UnaryOp::Neg => v.wrapping_neg(),
+                    UnaryOp::Not => !v,
+                    UnaryOp::LogicalNot => (v == 0) as u64,
+                }
+            }
+            ExprKind::Binary(left, op, right) => {
+                let (a, b) = (self.fold(left)?, self.fold(right)?);
+                let signed = left.ty.is_signed();
+                if matches!(op, Op::Div | Op::Rem) && b == 0 {
+                    return Err(Diagnostic::new("division by zero in a constant", right.span).with_label("this is zero"));
+                }
+                match op {
+                    Op::Add => a.wrapping_add(b),
+                    Op::Sub => a.wrapping_sub(b),
+                    Op::Mul => a.wrapping_mul(b),
+                    Op::Div if signed => (a as i64).wrapping_div(b as i64) as u64,
+                    Op::Div => a / b,
+                    Op::Rem if signed => (a as i64).wrapping_rem(b as i64) as u64,
+                    Op::Rem => a % b,
+                    Op::Shl => a.wrapping_shl(b as u32),
+                    Op::Shr if signed => (a as i64).wrapping_shr(b as u32) as u64,
+                    Op::Shr => a.wrapping_shr(b as u32),
+                    Op::Or => a | b,
+                    Op::And => a & b,
+                    Op::Xor => a ^ b,
+                    Op::LogicalOr => (a != 0 || b != 0) as u64,
+                    Op::LogicalAnd => (a != 0 && b != 0) as u64,
+                    Op::Eq => (a == b) as u64,
+                    Op::Ne => (a != b) as u64,
+                    Op::Lt if signed => ((a as i64) < b as i64) as u64,
+                    Op::Le if signed => (a as i64 <= b as i64) as u64,
+                    Op::Gt if signed => (a as i64 > b as i64) as u64,
+                    Op::Ge if signed => (a as i64 >= b as i64) as u64,
+                    Op::Lt => (a < b) as u64,
+                    Op::Le => (a <= b) as u64,
+                    Op::Gt => (a > b) as u64,
+                    Op::Ge => (a >= b) as u64,
+                }
+            }
+            _ => return Err(Diagnostic::new("global initializers must be constant", expr.span)
+                .with_label("not known at compile time")),
+        };
+        Ok(self.wrap(value, &expr.ty))
+    }
+
+    /// `value` wrapped to the width of `ty`, sign-extended to 64 bits if `ty` is signed.
+    fn wrap(&self, value: u64, ty: &Type) -> u64 {
+        let bits = 8 * self.size_of(ty);
+        if bits >= 64 { return value; }
+        let value = value & ((1 << bits) - 1);
+        let negative = ty.is_signed() && value >> (bits - 1) != 0;
+        if negative { value | !((1 << bits) - 1) } else { value }
+    }
+
+    fn check_function(&mut self, func: &Function) -> Function<Expr> {
+        self.scopes = vec![func.params.iter().map(|p| (p.name.clone(), p.ty.clone())).collect()];
+        self.ret_type = func.ret_type.clone();
+        let body = self.check_block(&func.body);
+        Function {
+            name: func.name.clone(), params: func.params.clone(), ret_type: func.ret_type.clone(),
+            body, attributes: func.attributes.clone(), span: func.span,
+        }
+    }
+
+    /// Checks `stmts` in a fresh scope. A broken statement is reported and dropped.
+    fn check_block(&mut self, stmts: &[Statement]) -> Vec<Statement<Expr>> {
+        self.scopes.push(HashMap::new());
+        let mut checked = Vec::new();
+        for stmt in stmts {
+            match self.check_statement(stmt) {
+                Ok(stmt) => checked.push(stmt),
+                Err(d) => self.errors.push(d),
+            }
+        }
+        self.scopes.pop();
+        checked
+    }
+
+    fn check_loop_body(&mut self, label: &Option<String>, body: &[Statement]) -> Vec<Statement<Expr>> {
+        self.loops.push(label.clone());
+        let body = self.check_block(body);
+        self.loops.pop();
+        body
+    }
+
+    fn check_statement(&mut self, stmt: &Statement) -> SResult<Statement<Expr>> {
+        Ok(match stmt {
+            Statement::Let { name, ty, value, volatile, span } => {
+                self.check_type(ty, *span)?;
+                let value = value.as_ref().map(|v| self.check_initializer(v, ty)).transpose();
+                // Declared after the initialiser, so `let x: u16 = x + 1;` reads the outer `x`,
+                // and declared even when the initialiser is broken, so later uses don't cascade.
+                self.scopes.last_mut().unwrap().insert(name.clone(), ty.clone());
+                if *ty == Type::Void {
+                    return Err(Diagnostic::new(format!("variable `{}` cannot have type `void`", name), *span));
+                }
+                Statement::Let { name: name.clone(), ty: ty.clone(), value: value?, volatile: *volatile, span: *span }
+            }
+            Statement::Expression(expr) => {
+                let expr = self.check_expr(expr, None)?;
+                if expr.ty.is_aggregate() { return Err(Self::aggregate_value(&expr.ty, expr.span)); }
+                Statement::Expression(expr)
+            }
+            Statement::Loop(label, body) => Statement::Loop(label.clone(), self.check_loop_body(label, body)),
+            Statement::While(label, cond, body) => {
+                let cond = self.check_condition(cond);
+                let body = self.check_loop_body(label, body);
+                Statement::While(label.clone(), cond?, body)
+            }
+            Statement::For { label, var, start, end, body } => {
+                let bounds = self.check_range(start, end);
+                let ty = bounds.as_ref().map(|(start, _)| start.ty.clone()).unwrap_or(Type::U16);
+                self.scopes.push(HashMap::from([(var.clone(), ty)]));
+                let body = self.check_loop_body(label, body);
+                self.scopes.pop();
+                let (start, end) = bounds?;
+                Statement::For { label: label.clone(), var: var.clone(), start, end, body }
+            }
+            Statement::Break(label, span) | Statement::Continue(label, span) => {
+                let is_break = matches!(stmt, Statement::Break(..));
+                let keyword = if is_break { "break" } else { "continue" };
+                match label {
+                    Some(name) if !self.loops.iter().any(|l| l.as_ref() == Some(name)) => {
+                        return Err(Diagnostic::new(format!("use of undeclared label `{}`", name), *span)
+                            .with_label("no enclosing loop has this label"));
+                    }
+                    None if self.loops.is_empty() => {
+                        return Err(Diagnostic::new(format!("`{}` outside of a loop", keyword), *span)
+                            .with_label(format!("cannot `{}` outside of a loop", keyword)));
+                    }
+                    _ => {}
+                }
+                if is_break { Statement::Break(label.clone(), *span) } else { Statement::Continue(label.clone(), *span) }
+            }
+            Statement::If(cond, then_block, else_block) => {
+                let cond = self.check_condition(cond);
+                let then_block = self.check_block(then_block);
+                let else_block = else_block.as_ref().map(|b| self.check_block(b));
+                Statement::If(cond?, then_block, else_block)
+            }
+            Statement::Asm(code) => Statement::Asm(code.clone()),
+            Statement::Assignment(target, value) => {
+                if let Ast::Variable(name) = &target.kind {
+                    if let Some(port) = self.port(name) {
+                        let ty = self.globals[name].clone();
+                        let value = self.check_as(value, &ty)?;
+                        return Ok(Statement::Expression(Self::port_access(port, &ty, Some(value), target.span)));
+                    }
+                }
+                let target = self.check_expr(target, None)?;
+                let mut place = &target;
+                while let ExprKind::Field(base, _) | ExprKind::Index(base, _) = &place.kind { place = base; }
+                match &place.kind {
+                    ExprKind::Global(name) if self.constants.contains(name) => {
+                        return Err(Diagnostic::new(format!("cannot assign to constant `{}`", name), target.span)
+                            .with_label("constants are read-only"));
+                    }
+                    ExprKind::ByteStr(_) => {
+                        return Err(Diagnostic::new("cannot assign into a byte string literal", target.span)
+                            .with_label("byte strings are read-only"));
+                    }
+                    _ => {}
+                }
+                let value = self.check_as(value, &target.ty)?;
+                Statement::Assignment(Box::new(target), Box::new(value))
+            }
+            Statement::Return(value, span) => {
+                let ret = self.ret_type.clone();
+                let value = match value {
+                    Some(value) if ret == Type::Void => {
+                        return Err(Diagnostic::new("`return` with a value in a function returning `void`", value.span)
+                            .with_label("remove this value"));
+                    }
+                    Some(value) => Some(self.check_as(value, &ret)?),
+                    None if ret != Type::Void => {
+                        return Err(Diagnostic::new(format!("`return;` in a function returning `{}`", ret), *span)
+                            .with_label(format!("expected a `{}` value", ret)));
+                    }
+                    None => None,
+                };
+                Statement::Return(value, *span)
+            }
+            Statement::Clear(span) | Statement::Newline(span) | Statement::Print(.., span) | Statement::Printf(.., span)
+                if !self.real_mode() =>
+            {
+                return Err(Diagnostic::new("console builtins are not supported by the 64-bit target", *span)
+                    .with_label("they use BIOS video services, which long mode cannot call"));
+            }
+            Statement::Clear(span) => Statement::Clear(*span),
+            Statement::Newline(span) => Statement::Newline(*span),
+            Statement::Print(text, color, span) => {
+                let color = color.as_ref().map(|c| self.check_as(c, &Type::U8)).transpose()?;
+                Statement::Print(text.clone(), color, *span)
+            }
+            Statement::Printf(pieces, color, span) => {
+                let pieces = pieces.iter().map(|piece| self.check_format(piece)).collect::<SResult<_>>()?;
+                let color = color.as_ref().map(|c| self.check_as(c, &Type::U8)).transpose()?;
+                Statement::Printf(pieces, color, *span)
+            }
+        })
+    }
+
+    /// Decimal output takes any integer, hex output also a near pointer.
+    fn check_format(&mut self, piece: &Format) -> SResult<Format<Expr>> {
+        Ok(match piece {
+            Format::Text(text) => Format::Text(text.clone()),
+            Format::Dec(value) => {
+                let value = self.check_expr(value, None)?;
+                if !value.ty.is_integer() {
+                    return Err(Diagnostic::new(format!("cannot print `{}` in decimal", value.ty), value.span)
+                        .with_label("expected an integer"));
+                }
+                Format::Dec(value)
+            }
+            Format::Hex(value) => {
+                let value = self.check_expr(value, None)?;
+                if !value.ty.is_integer() && !matches!(value.ty, Type::Pointer(_)) {
+                    return Err(Diagnostic::new(format!("cannot print `{}` in hex", value.ty), value.span)
+                        .with_label("expected an integer or a near pointer"));
+                }
+                Format::Hex(value)
+            }
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Option<Type> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).cloned())
+    }
+
+    /// The port of `name` if it refers to a `#[port(N)]` global, i.e. no local shadows it.
+    fn port(&self, name: &str) -> Option<u64> {
+        if self.lookup(name).is_some() { return None; }
+        self.ports.get(name).copied()
+    }
+
+    /// A read of the port global of type `ty`, or a write of `value` to it.
+    fn port_access(port: u64, ty: &Type, value: Option<Expr>, span: Span) -> Expr {
+        let port = Expr { kind: ExprKind::Number(port), ty: Type::U16, span };
+        let (read, write) = match ty {
+            Type::U8 => (Builtin::Inb, Builtin::Outb),
+            Type::U16 => (Builtin::Inw, Builtin::Outw),
+            _ => (Builtin::Inl, Builtin::Outl),
+        };
+        match value {
+            None => Expr { kind: ExprKind::Builtin(read, vec![port]), ty: ty.clone(), span },
+            Some(value) => Expr { kind: ExprKind::Builtin(write, vec![port, value]), ty: Type::Void, span },
+        }
+    }
+
+    fn real_mode(&self) -> bool { self.pointer_size == 2 }
+
+    /// Pointer-sized unsigned integer, the type of pointer offsets.
+    fn usize(&self) -> Type {
+        if self.pointer_size == 2 { Type::U16 } else { Type::U64 }
+    }
+
+    fn size_of(&self, ty: &Type) -> u64 {
+        match ty {
+            Type::U8 | Type::I8 | Type::Bool => 1,
+            Type::U16 | Type::I16 => 2,
+            Type::U32 | Type::I32 => 4,
+            Type::U64 | Type::I64 => 8,
+            Type::Pointer(_) => self.pointer_size,
+            Type::FarPointer(_) if self.real_mode() => 4,
+            Type::FarPointer(_) => self.pointer_size,
+            Type::Struct(name) => self.structs.get(name).map_or(0, |def| def.size),
+            Type::Array(element, len) => self.size_of(element).saturating_mul(*len),
+            Type::Void => 0,
+        }
+    }
+
+    /// Alignment of a `ty` field in a struct with natural layout.
+    fn align_of(&self, ty: &Type) -> u64 {
+        match ty {
+            Type::Struct(name) => self.structs.get(name).map_or(1, |def| def.align),
+            Type::Array(element, _) => self.align_of(element),
+            Type::FarPointer(_) if self.real_mode() => 2,
+            _ => self.size_of(ty).max(1),
+        }
+    }
+
+    /// Whether a `from` value converts to `to` without a cast.
+    fn widens(&self, from: &Type, to: &Type) -> bool {
+        if let (Type::Pointer(near), Type::FarPointer(far)) = (from, to) { return near == far; }
+        from.is_integer() && to.is_integer() && self.size_of(from) < self.size_of(to) && (!from.is_signed() || to.is_signed())
+    }
+
+    fn cast(expr: Expr, ty: &Type) -> Expr {
+        let span = expr.span;
+        Expr { kind: ExprKind::Cast(Box::new(expr)), ty: ty.clone(), span }
+    }
+
+    /// Converts `expr` to `ty`, implicitly widening if needed.
+    fn coerce(&self, expr: Expr, ty: &Type) -> SResult<Expr> {
+        if expr.ty.is_aggregate() { return Err(Self::aggregate_value(&expr.ty, expr.span)); }
+        if expr.ty == *ty { return Ok(expr); }
+        if self.widens(&expr.ty, ty) { return Ok(Self::cast(expr, ty)); }
+        let castable = |t: &Type| t.is_integer() || t.is_pointer() || *t == Type::Bool;
+        let label = if castable(&expr.ty) && castable(ty) { format!("convert it with `cast<{}>(...)`", ty) }
+            else { format!("expected `{}`", ty) };
+        Err(Diagnostic::new(format!("mismatched types: expected `{}`, found `{}`", ty, expr.ty), expr.span).with_label(label))
+    }
+
+    /// Checks `expr` where a `ty` is expected and converts it.
+    fn check_as(&mut self, expr: &Expression, ty: &Type) -> SResult<Expr> {
+        let expr = self.check_expr(expr, Some(ty))?;
+        self.coerce(expr, ty)
+    }
+
+    fn check_condition(&mut self, expr: &Expression) -> SResult<Expr> {
+        let cond = self.check_expr(expr, None)?;
+        if cond.ty.is_far() {
+            // Null means segment and offset both zero, which the backends test as `!= 0`.
+            let (ty, span) = (cond.ty.clone(), cond.span);
+            let null = Expr { kind: ExprKind::Number(0), ty, span };
+            return Ok(Expr { kind: ExprKind::Binary(Box::new(cond), Op::Ne, Box::new(null)), ty: Type::Bool, span });
+        }
+        if cond.ty == Type::Bool || cond.ty.is_integer() || cond.ty.is_pointer() { return Ok(cond); }
+        Err(Diagnostic::new(format!("mismatched types: expected `bool`, found `{}`", cond.ty), cond.span)
+            .with_label("expected a condition"))
+    }
+
+    fn check_range(&mut self, start: &Expression, end: &Expression) -> SResult<(Expr, Expr)> {
+        let (start, end) = self.check_operands(start, end, None)?;
+        let span = start.span.to(end.span);
+        let (start, end) = self.unify(start, end, span, "range")?;
+        if !start.ty.is_integer() {
+            return Err(Diagnostic::new(format!("`for` ranges must be integers, found `{}`", start.ty), span));
+        }
+        Ok((start, end))
+    }
+
+    /// A literal, or arithmetic on literals only: it takes its type from context.
+    fn is_untyped(expr: &Expression) -> bool {
+        match &expr.kind {
+            Ast::Number(_, suffix) => suffix.is_none(),
+            Ast::Unary(UnaryOp::Neg | UnaryOp::Not, operand) => Self::is_untyped(operand),
+            Ast::BinaryOp(left, op, right) if !op.is_comparison() && !op.is_logical() => {
+                Self::is_untyped(left) && Self::is_untyped(right)
+            }
+            _ => false,
+        }
+    }
+
+    /// Checks both operands of a binary operator. A literal on one side takes
+    /// the type of the other side; otherwise types flow left to right.
+    fn check_operands(&mut self, left: &Expression, right: &Expression, expected: Option<&Type>) -> SResult<(Expr, Expr)> {
+        if Self::is_untyped(left) && !Self::is_untyped(right) {
+            let right = self.check_expr(right, expected)?;
+            let left = self.check_expr(left, self.literal_context(left, &right.ty))?;
+            Ok((left, right))
+        } else {
+            let left = self.check_expr(left, expected)?;
+            let right = self.check_expr(right, self.literal_context(right, &left.ty))?;
+            Ok((left, right))
+        }
+    }
+
+    /// The context for an operand next to one of type `ty`: `ty`, unless the operand
+    /// is a constant too wide for it whose own type `ty` widens to (`'B' | 0x0F00`).
+    fn literal_context<'a>(&self, operand: &Expression, ty: &'a Type) -> Option<&'a Type> {
+        if Self::is_untyped(operand) && self.check_constant(operand, None, Some(ty)).is_err() {
+            let widens = self.check_constant(operand, None, None).is_ok_and(|own| self.widens(ty, &own.ty));
+            if widens { return None; }
+        }
+        Some(ty)
+    }
+
+    /// Widens one operand to the other's type, or fails if neither widens.
+    fn unify(&self, left: Expr, right: Expr, span: Span, what: &str) -> SResult<(Expr, Expr)> {
+        if left.ty == right.ty { return Ok((left, right)); }
+        if self.widens(&left.ty, &right.ty) { let ty = right.ty.clone(); return Ok((Self::cast(left, &ty), right)); }
+        if self.widens(&right.ty, &left.ty) { let ty = left.ty.clone(); return Ok((left, Self::cast(right, &ty))); }
+        Err(Diagnostic::new(format!("mismatched types `{}` and `{}` in {}", left.ty, right.ty, what), span)
+            .with_label("convert one side with `cast<T>(...)`"))
+    }
+
+    /// The type a constant expression (integer literals and arithmetic on them
+    /// only) is folded in: `Some(None)` without suffixes, `Some(Some(ty))` when its
+    /// suffixes all say `ty`, and `None` when it is not one or they disagree.
+    fn constant_type(expr: &Expression) -> Option<Option<Type>> {
+        match &expr.kind {
+            Ast::Number(_, suffix) => Some(suffix.clone()),
+            Ast::Unary(UnaryOp::Neg | UnaryOp::Not, operand) => Self::constant_type(operand),
+            Ast::BinaryOp(left, op, right) if !op.is_comparison() && !op.is_logical() => {
+                let (left, right) = (Self::constant_type(left)?, Self::constant_type(right)?);
+                match (left, right) {
+                    // The shift count does not have to match the shifted value.
+                    (left, _) if matches!(op, Op::Shl | Op::Shr) => Some(left),
+                    (Some(a), Some(b)) if a != b => None,
+                    (left, right) => Some(left.or(right)),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// A constant expression, folded exactly and then checked against its type:
+    /// that of its suffix, else of its context, else the smallest of
+    /// `u16`/`u32`/`u64` or `i16`/`i32`/`i64` holding it (only `u16` and `i16`
+    /// in real mode).
+    fn check_constant(&self, expr: &Expression, suffix: Option<&Type>, expected: Option<&Type>) -> SResult<Expr> {
+        let ty = match (suffix, expected) {
+            (Some(ty), _) if self.real_mode() && Self::is_wide(ty) => return Err(Self::too_wide(ty, expr.span)),
+            (Some(ty), _) => ty.clone(),
+            (None, Some(t)) if t.is_integer() || t.is_pointer() => t.clone(),
+            (None, _) => {
+                let candidates: &[Type] = if self.real_mode() { &[Type::U16, Type::I16] }
+                    else { &[Type::U16, Type::U32, Type::U64, Type::I16, Type::I32, Type::I64] };
+                let mut value = 0;
+                for ty in candidates {
+                    value = self.eval_constant(expr, ty)?;
+                    if Self::integer_range(ty).contains(&value) { return self.constant_as(expr, value, ty.clone()); }
+                }
+                let ty = if value < 0 { candidates.last() } else { candidates.iter().rfind(|t| !t.is_signed()) };
+                return self.constant_as(expr, value, ty.unwrap().clone());
+            }
+        };
+        let value = self.eval_constant(expr, &ty)?;
+        self.constant_as(expr, value, ty)
+    }
+
+    /// The folded `value` of the constant `expr` as a literal of type `ty`, if it is one.
+    fn constant_as(&self, expr: &Expression, value: i128, ty: Type) -> SResult<Expr> {
+        let span = expr.span;
+        let range = if ty.is_pointer() { 0..=u64::MAX as i128 } else { Self::integer_range(&ty) };
+        if !range.contains(&value) {
+            let literal = match &expr.kind {
+                Ast::Number(..) => true,
+                Ast::Unary(UnaryOp::Neg, operand) => matches!(operand.kind, Ast::Number(..)),
+                _ => false,
+            };
+            let what = if literal { "literal" } else { "constant" };
+            return Err(Diagnostic::new(format!("{} `{}` does not fit in `{}`", what, value, ty), span)
+                .with_label(format!("`{}` ranges from {} to {}", ty, range.start(), range.end())));
+        }
+        let n = value as u64;
+        if self.real_mode() && ty.is_pointer() {
+            if ty.is_far() && n > 0xFFFFF {
+                return Err(Diagnostic::new(format!("address {:#X} is beyond the 1 MiB real-mode address space", n), span)
+                    .with_label("not reachable from a 16-bit kernel"));
+            }
+            if !ty.is_far() && n > 0xFFFF {
+                return Err(Diagnostic::new(format!("address {:#X} does not fit in a near pointer", n), span)
+                    .with_label(format!("near pointers reach the first 64 KiB; use `far {}`", ty)));
+            }
+        }
+        Ok(Expr { kind: ExprKind::Number(n), ty, span })
+    }
+
+    /// Evaluates a constant expression exactly, with `~` flipping the bits of a `ty`.
+    fn eval_constant(&self, expr: &Expression, ty: &Type) -> SResult<i128> {
+        let overflow = || Diagnostic::new("constant expression overflows", expr.span)
+            .with_label("its value does not fit in 128 bits");
+        let value = match &expr.kind {
+            Ast::Number(n, _) => Some(*n as i128),
+            Ast::Unary(UnaryOp::Neg, operand) => self.eval_constant(operand, ty)?.checked_neg(),
+            Ast::Unary(UnaryOp::Not, operand) => {
+                let value = !self.eval_constant(operand, ty)?;
+                let bits = 8 * self.size_of(ty).max(1);
+                Some(if ty.is_signed() || bits >= 128 { value } else { value & ((1 << bits) - 1) })
+            }
+            Ast::BinaryOp(left, op, right) => {
+                let (a, b) = (self.eval_constant(left, ty)?, self.eval_constant(right, ty)?);
+                if matches!(op, Op::Div | Op::Rem) && b == 0 {
+                    return Err(Diagnostic::new("division by zero in a constant", right.span).with_label("this is zero"));
+                }
+                match op {
+                    Op::Add => a.checked_add(b),
+                    Op::Sub => a.checked_sub(b),
+                    Op::Mul => a.checked_mul(b),
+                    Op::Div => a.checked_div(b),
+                    Op::Rem => a.checked_rem(b),
+                    Op::Shl => (0..127).contains(&b).then(|| a.checked_mul(1 << b)).flatten(),
+                    Op::Shr => (b >= 0).then(|| a >> b.min(127)),
+                    Op::Or => Some(a | b),
+                    Op::And => Some(a & b),
+                    Op::Xor => Some(a ^ b),
+                    _ => unreachable!("constant_type excludes comparisons and logical operators"),
+                }
+            }
+            _ => unreachable!("constant_type only accepts literals and arithmetic on them"),
+        };
+        value.ok_or_else(overflow)
+    }
+
+    /// The lowest and highest values of an integer `ty`.
+    fn integer_range(ty: &Type) -> std::ops::RangeInclusive<i128> {
+        let bits = match ty { Type::U8 | Type::I8 => 8, Type::U16 | Type::I16 => 16, Type::U32 | Type::I32 => 32, _ => 64 };
+        if ty.is_signed() { -(1 << (bits - 1))..=(1 << (bits - 1)) - 1 } else { 0..=(1 << bits) - 1 }
+    }
+
+    fn check_expr(&mut self, expr: &Expression, expected: Option<&Type>) -> SResult<Expr> {
+        let span = expr.span;
+        if let Some(suffix) = Self::constant_type(expr) {
+            return self.check_constant(expr, suffix.as_ref(), expected);
+        }
+        let (kind, ty) = match &expr.kind {
+            Ast::Number(..) => unreachable!("literals are constants"),
+            Ast::Bool(b) => (ExprKind::Number(*b as u64), Type::Bool),
+            Ast::Char(c) => (ExprKind::Number(*c as u64), Type::U8),
+            Ast::Str(text) => (ExprKind::Str(text.clone()), Type::Pointer(Box::new(Type::U8))),
+            Ast::ByteStr(bytes) => (ExprKind::ByteStr(bytes.clone()), Type::Array(Box::new(Type::U8), bytes.len() as u64)),
+            Ast::Variable(name) => {
+                if let Some(ty) = self.lookup(name) {
+                    (ExprKind::Local(name.clone()), ty)
+                } else if let Some(port) = self.port(name) {
+                    return Ok(Self::port_access(port, &self.globals[name], None, span));
+                } else if let Some(ty) = self.globals.get(name) {
+                    (ExprKind::Global(name.clone()), ty.clone())
+                } else {
+                    return Err(Diagnostic::new(format!("cannot find value `{}` in this scope", name), span)
+                        .with_label("not found in this scope"));
+                }
+            }
+            Ast::BinaryOp(left, op, right) => return self.check_binary(left, op, right, expected, span),
+            Ast::Dereference(pointer) => {
+                let pointer = self.check_expr(pointer, None)?;
+                let ty = match pointer.ty.pointee() {
+                    Some(Type::Void) => return Err(Diagnostic::new("cannot dereference a `*void`", span)
+                        .with_label("cast it to a typed pointer first")),
+                    Some(pointee) => pointee.clone(),
+                    None => return Err(Diagnostic::new(format!("type `{}` cannot be dereferenced", pointer.ty), span)
+                        .with_label("not a pointer")),
+                };
+                (ExprKind::Dereference(Box::new(pointer)), ty)
+            }
+            Ast::AddressOf(place) => match &place.kind {
+                Ast::Variable(name) if self.port(name).is_some() => {
+                    return Err(Diagnostic::new(format!("cannot take the address of port global `{}`", name), span)
+                        .with_label("it lives in I/O space, not in memory"));
+                }
+                Ast::Variable(_) | Ast::Dereference(_) | Ast::Field(..) | Ast::Index(..) | Ast::ByteStr(_) => {
+                    let place = self.check_expr(place, None)?;
+                    return Ok(self.address_of(place, span));
+                }
+                _ => return Err(Diagnostic::new("cannot take the address of this expression", span)
+                    .with_label("only variables, dereferences, fields and elements have an address")),
+            },
+            Ast::Field(base, field) => {
+                let base = self.check_expr(base, None)?;
+                // `p.field` through a pointer is `(*p).field`
+                let base = match base.ty.pointee() {
+                    Some(pointee @ Type::Struct(_)) => {
+                        let (ty, span) = (pointee.clone(), base.span);
+                        Expr { kind: ExprKind::Dereference(Box::new(base)), ty, span }
+                    }
+                    _ => base,
+                };
+                let Type::Struct(name) = &base.ty else {
+                    return Err(Diagnostic::new(format!("type `{}` has no fields", base.ty), base.span)
+                        .with_label("not a struct or a pointer to one"));
+                };
+                let Some(found) = self.structs[name].fields.iter().find(|f| f.name == *field) else {
+                    return Err(Diagnostic::new(format!("no field `{}` on struct `{}`", field, name), span)
+                        .with_label("unknown field"));
+                };
+                let (offset, ty) = (found.offset, found.ty.clone());
+                (ExprKind::Field(Box::new(base), offset), ty)
+            }
+            Ast::Index(base, index) => return self.check_index(base, index, span),
+            Ast::Array(_) => {
+                return Err(Diagnostic::new("array literals can only initialize a `let` or a global", span)
+                    .with_label("assign the elements one by one"));
+            }
+            Ast::Unary(op, operand) => {
+                let operand = match op {
+                    UnaryOp::LogicalNot => self.check_condition(operand)?,
+                    UnaryOp::Neg => self.check_expr(operand, expected.or(Some(&Type::I16)))?,
+                    UnaryOp::Not => self.check_expr(operand, expected)?,
+                };
+                let ty = match op {
+                    UnaryOp::LogicalNot => Type::Bool,
+                    UnaryOp::Neg if !operand.ty.is_signed() => {
+                        return Err(Diagnostic::new(format!("cannot apply unary `-` to type `{}`", operand.ty), span)
+                            .with_label("only signed integers can be negated"));
+                    }
+                    UnaryOp::Not if !operand.ty.is_integer() => {
+                        return Err(Diagnostic::new(format!("cannot apply unary `~` to type `{}`", operand.ty), span)
+                            .with_label("expected an integer"));
+                    }
+                    _ => operand.ty.clone(),
+                };
+                (ExprKind::Unary(op.clone(), Box::new(operand)), ty)
+            }
+            Ast::Call(name, args) => {
+                let builtin = Builtin::from_name(name);
+                let Some((params, ret)) = builtin.map(Builtin::signature).or_else(|| self.signatures.get(name).cloned()) else {
+                    return Err(Diagnostic::new(format!("cannot find function `{}`", name), span)
+                        .with_label("not defined in this file"));
+                };
+                if let Some(ty) = params.iter().chain([&ret]).find(|ty| self.real_mode() && Self::is_wide(ty)) {
+                    return Err(Diagnostic::new(format!("`{}` needs `{}`, which is not supported by the 16-bit target", name, ty), span)
+                        .with_label("real-mode values are at most 16 bits wide"));
+                }
+                if builtin == Some(Builtin::SetVideoMode) && !self.real_mode() {
+                    return Err(Diagnostic::new("`set_video_mode` is not supported by the 64-bit target", span)
+                        .with_label("it uses BIOS video services, which long mode cannot call"));
+                }
+                if self.handlers.contains(name) {
+                    return Err(Diagnostic::new(format!("interrupt handler `{}` cannot be called directly", name), span)
+                        .with_label("it returns with `iret`"));
+                }
+                if params.len() != args.len() {
+                    let msg = format!("function `{}` takes {} argument(s) but {} were supplied", name, params.len(), args.len());
+                    return Err(Diagnostic::new(msg, span));
+                }
+                let mut checked = Vec::new();
+                for (arg, ty) in args.iter().zip(&params) {
+                    checked.push(self.check_as(arg, ty)?);
+                }
+                match builtin {
+                    Some(builtin) => (ExprKind::Builtin(builtin, checked), ret),
+                    None => (ExprKind::Call(name.clone(), checked), ret),
+                }
+            }
+            Ast::Cast(ty, value) => {
+                self.check_type(ty, span)?;
+                // A constant cast to an integer is truncated to it, whatever its range
+                if let (true, Some(suffix)) = (ty.is_integer(), Self::constant_type(value)) {
+                    let folded = match &suffix {
+                        Some(own) => self.check_constant(value, Some(own), None)?.constant().unwrap(),
+                        None => self.eval_constant(value, ty)? as u64,
+                    };
+                    return Ok(Expr { kind: ExprKind::Number(self.wrap(folded, ty)), ty: ty.clone(), span });
+                }
+                let value = self.check_expr(value, Some(ty))?;
+                let castable = |t: &Type| t.is_integer() || t.is_pointer() || *t == Type::Bool;
+                if !castable(&value.ty) || !castable(ty) {
+                    return Err(Diagnostic::new(format!("cannot cast `{}` to `{}`", value.ty, ty), span));
+                }
+                if value.ty == *ty { return Ok(Expr { span, ..value }); }
+                if self.real_mode() && value.ty.is_far() && ty.is_pointer() && !ty.is_far() {
+                    return Err(Diagnostic::new(format!("cannot cast `{}` to `{}`", value.ty, ty), span)
+                        .with_label("a near pointer has no segment, so this one would be lost"));
+                }
+                (ExprKind::Cast(Box::new(value)), ty.clone())
+            }
+        };
+        Ok(Expr { kind, ty, span })
+    }
+
+    /// `&place`: `&*p` is `p`, and a field's address is its struct's plus the
+    /// field offset. Real-mode globals above 64 KiB give a far pointer.
+    fn address_of(&self, place: Expr, span: Span) -> Expr {
+        let wrap = |far: bool, pointee: Type| if far { Type::FarPointer(Box::new(pointee)) } else { Type::Pointer(Box::new(pointee)) };
+        match place.kind {
+            ExprKind::Dereference(pointer) => Expr { span, ..*pointer },
+            ExprKind::Field(base, offset) => {
+                let offset = Expr { kind: ExprKind::Number(offset), ty: self.usize(), span };
+                self.address_of_part(*base, offset, place.ty, span)
+            }
+            ExprKind::Index(base, offset) => self.address_of_part(*base, *offset, place.ty, span),
+            kind => {
+                let high = match &kind {
+                    ExprKind::Global(name) => self.addresses.get(name).is_some_and(|&addr| addr > 0xFFFF),
+                    _ => false,
+                };
+                let ty = wrap(self.real_mode() && high, place.ty.clone());
+                Expr { kind: ExprKind::AddressOf(Box::new(Expr { kind, ..place })), ty, span }
+            }
+        }
+    }
+
+    /// The address of a field or element `offset` bytes into `base`, as a pointer to a `ty`.
+    fn address_of_part(&self, base: Expr, offset: Expr, ty: Type, span: Span) -> Expr {
+        let base = self.address_of(base, span);
+        let ty = if base.ty.is_far() { Type::FarPointer(Box::new(ty)) } else { Type::Pointer(Box::new(ty)) };
+        let address = if offset.constant() == Some(0) { base } else {
+            let pointer = base.ty.clone();
+            Expr { kind: ExprKind::Binary(Box::new(base), Op::Add, Box::new(offset)), ty: pointer, span }
+        };
+        Expr { kind: ExprKind::Cast(Box::new(address)), ty, span }
+    }
+
+    /// `base[index]`: an element of an array, or `*(base + index)` for a pointer.
+    fn check_index(&mut self, base: &Expression, index: &Expression, span: Span) -> SResult<Expr> {
+        let base = self.check_expr(base, None)?;
+        match base.ty.clone() {
+            Type::Array(element, len) => {
+                let offset = self.check_pointer_offset(&element, index)?;
+                let size = self.size_of(&element);
+                let offset = match self.fold(&offset) {
+                    Ok(bytes) if size > 0 && bytes / size >= len => {
+                        return Err(Diagnostic::new(format!("index {} is out of bounds for `{}`", bytes / size, base.ty), index.span)
+                            .with_label(format!("the last element is {}", len.saturating_sub(1))));
+                    }
+                    Ok(bytes) => Expr { kind: ExprKind::Number(bytes), ..offset },
+                    Err(_) => offset,
+                };
+                Ok(Expr { kind: ExprKind::Index(Box::new(base), Box::new(offset)), ty: *element, span })
+            }
+            Type::Pointer(pointee) | Type::FarPointer(pointee) => {
+                if *pointee == Type::Void {
+                    return Err(Diagnostic::new("cannot index a `*void`", span).with_label("cast it to a typed pointer first"));
+                }
+                let offset = self.check_pointer_offset(&pointee, index)?;
+                let offset = match self.fold(&offset) {
+                    Ok(bytes) => Expr { kind: ExprKind::Number(bytes), ..offset },
+                    Err(_) => offset,
+                };
+                let ty = base.ty.clone();
+                let pointer = Expr { kind: ExprKind::Binary(Box::new(base), Op::Add, Box::new(offset)), ty, span };
+                Ok(Expr { kind: ExprKind::Dereference(Box::new(pointer)), ty: *pointee, span })
+            }
+            ty => Err(Diagnostic::new(format!("cannot index into a value of type `{}`", ty), base.span)
+                .with_label("expected an array or a pointer")),
+        }
+    }
+
+    fn check_binary(&mut self, left: &Expression, op: &Op, right: &Expression, expected: Option<&Type>, span: Span) -> SResult<Expr> {
+        let binary = |left: Expr, right: Expr, ty: Type| Expr { kind: ExprKind::Binary(Box::new(left), op.clone(), Box::new(right)), ty, span };
+        if op.is_logical() {
+            let left = self.check_condition(left)?;
+            let right = self.check_condition(right)?;
+            return Ok(binary(left, right, Type::Bool));
+        }
+        if matches!(op, Op::Shl | Op::Shr) {
+            // The shift count does not have to match the shifted value.
+            let value = self.check_expr(left, expected)?;
+            let count = if Self::is_untyped(right) { self.check_expr(right, Some(&value.ty))? } else { self.check_expr(right, None)? };
+            for side in [&value, &count] {
+                if !side.ty.is_integer() { return Err(Self::bad_operand(op, &side.ty, span)); }
+            }
+            let ty = value.ty.clone();
+            return Ok(binary(value, count, ty));
+        }
+        let expected = if op.is_comparison() { None } else { expected };
+        if matches!(op, Op::Add | Op::Sub) && !Self::is_untyped(left) {
+            let left = self.check_expr(left, expected)?;
+            if let Some(pointee) = left.ty.pointee() {
+                let offset = self.check_pointer_offset(&pointee.clone(), right)?;
+                let ty = left.ty.clone();
+                return Ok(binary(left, offset, ty));
+            }
+            let right = self.check_expr(right, self.literal_context(right, &left.ty))?;
+            return self.finish_binary(left, op, right, span);
+        }
+        let (left, right) = self.check_operands(left, right, expected)?;
+        self.finish_binary(left, op, right, span)
+    }
+
+    /// Unifies the operand types and checks `op` applies to them.
+    fn finish_binary(&self, left: Expr, op: &Op, right: Expr, span: Span) -> SResult<Expr> {
+        let (left, right) = self.unify(left, right, span, &format!("`{}`", op.symbol()))?;
+        let ty = left.ty.clone();
+        let ok = match op {
+            Op::Eq | Op::Ne => ty.is_integer() || ty.is_pointer() || ty == Type::Bool,
+            Op::Lt | Op::Le | Op::Gt | Op::Ge => ty.is_integer() || ty.is_pointer(),
+            Op::And | Op::Or | Op::Xor => ty.is_integer() || ty == Type::Bool,
+            _ => ty.is_integer(),
+        };
+        if !ok { return Err(Self::bad_operand(op, &ty, span)); }
+        let ty = if op.is_comparison() { Type::Bool } else { ty };
+        Ok(Expr { kind: ExprKind::Binary(Box::new(left), op.clone(), Box::new(right)), ty, span })
+    }
+
+    /// The offset of `ptr + n` / `ptr - n`, scaled to bytes.
+    fn check_pointer_offset(&mut self, pointee: &Type, offset: &Expression) -> SResult<Expr> {
+        let usize = self.usize();
+        let offset = self.check_expr(offset, Some(&usize))?;
+        if !offset.ty.is_integer() {
+            return Err(Diagnostic::new(format!("cannot offset a pointer by `{}`", offset.ty), offset.span)
+                .with_label("expected an integer"));
+        }
+        let offset = if offset.ty == usize { offset } else { Self::cast(offset, &usize) };
+        let size = match pointee { Type::Void => 1, ty => self.size_of(ty) };
+        if size == 1 { return Ok(offset); }
+        let span = offset.span;
+        let size = Expr { kind: ExprKind::Number(size), ty: usize.clone(), span };
+        Ok(Expr { kind: ExprKind::Binary(Box::new(offset), Op::Mul, Box::new(size)), ty: usize, span })
+    }
+
+    fn bad_operand(op: &Op, ty: &Type, span: Span) -> Diagnostic {
+        Diagnostic::new(format!("cannot apply `{}` to type `{}`", op.symbol(), ty), span)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn check(source: &str, pointer_size: u64) -> Result<Program<Expr>, Vec<Diagnostic>> {
+        let program = Parser::new(Lexer::new(source).tokenize().unwrap()).parse_program().unwrap();
+        Sema::new(pointer_size).check(&program)
+    }
+
+    fn errors(source: &str, pointer_size: u64) -> Vec<String> {
+        check(source, pointer_size).unwrap_err().into_iter().map(|d| d.message).collect()
+    }
+
+    /// Each error's message with the source text its span covers.
+    fn spanned(source: &str, pointer_size: u64) -> Vec<(String, &str)> {
+        check(source, pointer_size).unwrap_err().into_iter().map(|d| (d.message, &source[d.span.start..d.span.end])).collect()
+    }
+
+    #[test]
+    fn wide_integers_are_rejected_on_the_16_bit_target() {
+        let source = "fn kernel_main() -> void {\n let x: u32 = 0x12345678;\n}\n";
+        assert_eq!(errors(source, 2), ["`u32` is not supported by the 16-bit target"]);
+        assert!(check(source, 8).is_ok());
+        let source = "fn twice(x: i64) -> i64 { return x + x; }\n";
+        assert_eq!(errors(source, 2), ["`i64` is not supported by the 16-bit target"; 2]);
+    }
+
+    #[test]
+    fn wide_port_builtins_are_rejected_on_the_16_bit_target() {
+        let source = "fn kernel_main() -> void {\n outl(0xCF8, 0x80000000);\n}\n";
+        assert_eq!(errors(source, 2), ["`outl` needs `u32`, which is not supported by the 16-bit target"]);
+        assert!(check(source, 8).is_ok());
+        let source = "fn kernel_main() -> void {\n print_dec(5u32);\n}\n";
+        assert_eq!(errors(source, 2), ["`u32` is not supported by the 16-bit target"]);
+        let source = "#[port(0xCFC)] volatile let PCI_DATA: u32;\n";
+        assert_eq!(errors(source, 2), ["`u32` is not supported by the 16-bit target"]);
+    }
+
+    #[test]
+    fn types_past_64_kib_are_rejected_on_the_16_bit_target() {
+        let source = "struct Big { a: [u8; 40000], b: [u8; 40000] }\nlet big: [u8; 70000];\n";
+        assert_eq!(errors(source, 2), ["struct `Big` is larger than 64 KiB", "`[u8; 70000]` is larger than 64 KiB"]);
+        assert!(check(source, 8).is_ok());
+    }
+
+    /// The value and type of the `let` initializers in `body`, in order.
+    fn lets(body: &str, pointer_size: u64) -> Vec<(u64, Type)> {
+        let program = check(&format!("fn kernel_main() -> void {{\n{}\n}}\n", body), pointer_size).unwrap();
+        program.functions[0].body.iter().map(|stmt| match stmt {
+            Statement::Let { value: Some(value), .. } => (value.constant().expect("a folded constant"), value.ty.clone()),
+            _ => panic!("expected an initialized `let`"),
+        }).collect()
+    }
+
+    #[test]
+    fn constant_arithmetic_is_checked_on_its_result() {
+        let source = "let TICKS_PER_SECOND: u16 = 1193182 / 65536;\nfn kernel_main() -> void {\n}\n";
+        let program = check(source, 2).unwrap();
+        assert_eq!(program.globals[0].value.as_ref().unwrap().constant(), Some(18));
+        assert_eq!(lets(" let a: u16 = 0xFFFF - 1;\n let b: i16 = -(3 * 4) + 2;\n let c: u16 = 1 << 15;\n let d: u8 = ~0;", 2), [
+            (0xFFFE, Type::U16), (-10i64 as u64, Type::I16), (0x8000, Type::U16), (0xFF, Type::U8),
+        ]);
+        let errors = errors("fn kernel_main() -> void {\n let a: u8 = 200 + 100;\n let b: u16 = 3 - 5;\n let c: u8 = 255u8 + 1;\n let d: u16 = 1 / 0;\n}\n", 2);
+        assert_eq!(errors, [
+            "constant `300` does not fit in `u8`", "constant `-2` does not fit in `u16`",
+            "constant `256` does not fit in `u8`", "division by zero in a constant",
+        ]);
+    }
+
+    #[test]
+    fn literals_are_checked_against_their_type() {
+        assert_eq!(lets(" let a: i8 = -128;\n let b: i16 = -0x8000;\n let c: u8 = 255;", 2), [
+            (-128i64 as u64, Type::I8), (-0x8000i64 as u64, Type::I16), (255, Type::U8),
+        ]);
+        let errors = errors("fn kernel_main() -> void {\n let a: i8 = -129;\n let b: u16 = -1;\n let c: u8 = 256;\n}\n", 2);
+        assert_eq!(errors, ["literal `-129` does not fit in `i8`", "literal `-1` does not fit in `u16`", "literal `256` does not fit in `u8`"]);
+        assert_eq!(lets(" let a: u64 = 0xFFFF_FFFF_FFFF_FFFF;\n let b: i64 = -9223372036854775808;", 8), [
+            (u64::MAX, Type::U64), (1 << 63, Type::I64),
+        ]);
+    }
+
+    #[test]
+    fn casts_truncate_constants() {
+        assert_eq!(lets(" let a: u8 = cast<u8>(-1);\n let b: u8 = cast<u8>(300);\n let c: i8 = cast<i8>(200u16);\n let d: u16 = cast<u16>(0x12345);", 2), [
+            (0xFF, Type::U8), (44, Type::U8), (-56i64 as u64, Type::I8), (0x2345, Type::U16),
+        ]);
+        // The operand itself must still fit its own suffix
+        assert_eq!(errors("fn kernel_main() -> void {\n let a: u8 = cast<u8>(256u8);\n}\n", 2), ["literal `256` does not fit in `u8`"]);
+    }
+
+    #[test]
+    fn a_literal_widens_to_a_narrower_operand() {
+        assert!(check("fn kernel_main() -> void {\n let a: u16 = 'B' | 0x0F00;\n}\n", 2).is_ok());
+        assert!(check("fn kernel_main() -> void {\n let a: u8 = 1;\n let b: u16 = a + 300;\n}\n", 2).is_ok());
+    }
+
+    #[test]
+    fn untyped_literals_default_to_a_word_on_the_16_bit_target() {
+        let source = "fn kernel_main() -> void {\n 0x10000;\n}\n";
+        assert_eq!(errors(source, 2).len(), 1);
+        assert!(check("fn kernel_main() -> void {\n print_dec(0xFFFF);\n print_dec(-1);\n}\n", 2).is_ok());
+        assert!(check(source, 8).is_ok());
+    }
+
+    #[test]
+    fn console_builtins_are_rejected_on_the_64_bit_target() {
+        let source = "fn kernel_main() -> void {\n clear();\n newline();\n print(\"hi\", 14);\n print_hex(0x1F);\n printf(\"{d}\", 7);\n}\n";
+        let message = "console builtins are not supported by the 64-bit target".to_string();
+        assert_eq!(spanned(source, 8), [
+            (message.clone(), "clear()"), (message.clone(), "newline()"), (message.clone(), "print(\"hi\", 14)"),
+            (message.clone(), "print_hex(0x1F)"), (message, "printf(\"{d}\", 7)"),
+        ]);
+        assert!(check(source, 2).is_ok());
+    }
+
+    #[test]
+    fn set_video_mode_is_rejected_on_the_64_bit_target() {
+        let source = "fn kernel_main() -> void {\n set_video_mode(0x13);\n put_pixel(1, 2, 4);\n}\n";
+        assert_eq!(spanned(source, 8), [("`set_video_mode` is not supported by the 64-bit target".to_string(), "set_video_mode(0x13)")]);
+        assert!(check(source, 2).is_ok());
+    }
+
+    /// The one error in `body`, placed in kernel_main after a `twice(n: u16, c: u8)`:
+    /// its message, the text under its span and its line.
+    fn error_in(body: &str) -> (String, String, usize) {
+        let source = format!("fn twice(n: u16, c: u8) -> u16 {{\n return n + n;\n}}\nfn kernel_main() -> void {{\n{}\n}}\n", body);
+        let [error] = &check(&source, 2).unwrap_err()[..] else { panic!("one error for {:?}", body) };
+        (error.message.clone(), source[error.span.start..error.span.end].to_string(), error.span.line)
+    }
+
+    #[test]
+    fn undefined_variables_are_reported_at_their_use() {
+        assert_eq!(error_in(" let a: u16 = missing + 1;"), ("cannot find value `missing` in this scope".into(), "missing".into(), 5));
+    }
+
+    #[test]
+    fn undefined_functions_are_reported_at_the_call() {
+        assert_eq!(error_in(" let a: u16 = nowhere(1);"), ("cannot find function `nowhere`".into(), "nowhere(1)".into(), 5));
+    }
+
+    #[test]
+    fn block_scoped_names_end_with_their_block() {
+        let body = " if 1 == 1 { let inner: u16 = 1; }\n let a: u16 = inner;";
+        assert_eq!(error_in(body), ("cannot find value `inner` in this scope".into(), "inner".into(), 6));
+    }
+
+    #[test]
+    fn pointers_are_not_assignable_to_integers() {
+        let body = " let text: *u8 = \"hi\";\n let a: u16 = text;";
+        assert_eq!(error_in(body), ("mismatched types: expected `u16`, found `*u8`".into(), "text".into(), 6));
+    }
+
+    #[test]
+    fn only_pointers_can_be_dereferenced() {
+        assert_eq!(error_in(" let n: u16 = 5;\n let a: u16 = *n;"), ("type `u16` cannot be dereferenced".into(), "*n".into(), 6));
+    }
+
+    #[test]
+    fn calls_check_the_argument_count() {
+        let message = "function `twice` takes 2 argument(s) but 1 were supplied";
+        assert_eq!(error_in(" let a: u16 = twice(1);"), (message.into(), "twice(1)".into(), 5));
+    }
+
+    #[test]
+    fn calls_check_the_argument_types() {
+        let body = " let p: *u8 = \"hi\";\n let a: u16 = twice(1, p);";
+        assert_eq!(error_in(body), ("mismatched types: expected `u8`, found `*u8`".into(), "p".into(), 6));
+    }
+
+    #[test]
+    fn far_pointers_do_not_cast_to_near_ones_on_the_16_bit_target() {
+        let body = " let vga: far *u16 = 0xB8000;\n let p: *u16 = cast<*u16>(vga);";
+        assert_eq!(error_in(body), ("cannot cast `far *u16` to `*u16`".into(), "cast<*u16>(vga)".into(), 6));
+        let source = "fn kernel_main() -> void {\n let vga: far *u16 = 0xB8000;\n let p: *u16 = cast<*u16>(vga);\n}\n";
+        assert!(check(source, 8).is_ok());
+        assert!(check("fn kernel_main() -> void {\n let p: *u16 = 0x500;\n let vga: far *u16 = p;\n}\n", 2).is_ok());
+    }
+
+    #[test]
+    fn structs_are_padded_unless_packed() {
+        let source = "struct Entry { tag: u8, value: u16, flag: u8 }\n#[packed]\nstruct Packed { tag: u8, value: u16, flag: u8 }\nfn kernel_main() -> void {\n}\n";
+        let program = check(source, 2).unwrap();
+        let layout = |def: &StructDef| (def.fields.iter().map(|f| f.offset).collect::<Vec<_>>(), def.size, def.align);
+        assert_eq!(layout(&program.structs[0]), (vec![0, 2, 4], 6, 2));
+        assert_eq!(layout(&program.structs[1]), (vec![0, 1, 3], 4, 1));
+    }
+}